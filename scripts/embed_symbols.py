@@ -0,0 +1,167 @@
+#!/usr/bin/env python3
+"""Embeds the function names of a kernel image into its .ksyms section.
+
+usage: embed_symbols.py <input.efi> <output.efi>
+
+The names are taken from the map file written by lld-link (-Clink-arg=/map
+in .cargo/config.toml), which is searched next to the input and in deps/.
+See src/symbols.rs for the layout of the section. Fails if the map or the
+section is not found, or the table does not fit in the section.
+"""
+
+import glob
+import os
+import re
+import struct
+import sys
+
+SECTION_NAME = b".ksyms"
+MAGIC = b"WSYMTAB\0"
+HEADER_SIZE = 16
+ENTRY_SIZE = 12
+
+# Escapes in the legacy Rust mangling
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+
+
+def fail(msg):
+    print(f"embed_symbols.py: {msg}", file=sys.stderr)
+    sys.exit(1)
+
+
+def demangle(name):
+    """Demangles a legacy Rust symbol (_ZN...E) and drops its hash."""
+    if not (name.startswith("_ZN") and name.endswith("E")):
+        return name
+    s = name[3:-1]
+    components = []
+    while s:
+        m = re.match(r"(\d+)", s)
+        if not m:
+            return name
+        n = int(m.group(1))
+        s = s[len(m.group(1)):]
+        components.append(s[:n])
+        s = s[n:]
+    if components and re.fullmatch(r"h[0-9a-f]{16}", components[-1]):
+        components.pop()
+    result = []
+    for c in components:
+        if c.startswith("_$"):
+            c = c[1:]
+        c = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), c)
+        for k, v in ESCAPES.items():
+            c = c.replace(k, v)
+        result.append(c.replace("..", "::"))
+    return "::".join(result)
+
+
+class PeImage:
+    def __init__(self, data):
+        (pe_offset,) = struct.unpack_from("<I", data, 0x3C)
+        if data[pe_offset:pe_offset + 4] != b"PE\0\0":
+            raise ValueError("not a PE image")
+        coff = pe_offset + 4
+        num_of_sections, self.timestamp = struct.unpack_from("<HI", data, coff + 2)
+        (size_of_optional_header,) = struct.unpack_from("<H", data, coff + 16)
+        self.sections = {}
+        table = coff + 20 + size_of_optional_header
+        for i in range(num_of_sections):
+            header = table + i * 40
+            name = data[header:header + 8].rstrip(b"\0")
+            virtual_size, rva, raw_size, raw_offset = struct.unpack_from(
+                "<IIII", data, header + 8
+            )
+            self.sections[name] = (rva, virtual_size, raw_offset, raw_size)
+
+
+def find_map(efi_path, timestamp):
+    """Returns the map written by the link which produced the image."""
+    d = os.path.dirname(efi_path)
+    for path in glob.glob(os.path.join(d, "*.map")) + glob.glob(
+        os.path.join(d, "deps", "*.map")
+    ):
+        with open(path) as f:
+            head = f.read(256)
+        m = re.search(r"Timestamp is ([0-9a-f]+)", head)
+        if m and int(m.group(1), 16) == timestamp:
+            return path
+    return None
+
+
+def parse_map(path):
+    """Returns (rva, mangled name) of the symbols in the map."""
+    symbols = []
+    with open(path) as f:
+        text = f.read()
+    m = re.search(r"Preferred load address is ([0-9a-f]+)", text)
+    image_base = int(m.group(1), 16)
+    for m in re.finditer(
+        r"^\s*[0-9a-f]{4}:[0-9a-f]{8}\s+(\S+)\s+([0-9a-f]{16})\s", text, re.MULTILINE
+    ):
+        addr = int(m.group(2), 16)
+        if addr >= image_base:
+            symbols.append((addr - image_base, m.group(1)))
+    return symbols
+
+
+def build_table(symbols, text_start, text_end):
+    names = {}
+    for rva, name in symbols:
+        if text_start <= rva < text_end and rva not in names:
+            names[rva] = demangle(name)
+    # An empty name marks the end of the last function
+    entries = sorted(names.items()) + [(text_end, "")]
+    strings = bytearray()
+    body = bytearray()
+    for rva, name in entries:
+        encoded = name.encode()
+        body += struct.pack("<III", rva, len(strings), len(encoded))
+        strings += encoded
+    strings_offset = HEADER_SIZE + len(body)
+    header = MAGIC + struct.pack("<II", len(entries), strings_offset)
+    return header + body + strings
+
+
+def embed(data, efi_path):
+    image = PeImage(data)
+    if SECTION_NAME not in image.sections or b".text" not in image.sections:
+        fail("no .ksyms section in the image")
+    _, _, offset, size = image.sections[SECTION_NAME]
+    if data[offset:offset + len(MAGIC)] != MAGIC:
+        fail("no symbol table in .ksyms section")
+    map_path = find_map(efi_path, image.timestamp)
+    if map_path is None:
+        fail("map file of the image is not found")
+    text_start, text_size, _, _ = image.sections[b".text"]
+    table = build_table(parse_map(map_path), text_start, text_start + text_size)
+    if len(table) > size:
+        fail(
+            f"symbol table ({len(table)} bytes) does not fit in .ksyms ({size} bytes). "
+            "Increase WASABI_SYMBOL_TABLE_SIZE in .cargo/config.toml."
+        )
+    return data[:offset] + table + data[offset + len(table):]
+
+
+def main():
+    if len(sys.argv) != 3:
+        print(__doc__, file=sys.stderr)
+        sys.exit(1)
+    with open(sys.argv[1], "rb") as f:
+        data = f.read()
+    data = embed(data, sys.argv[1])
+    with open(sys.argv[2], "wb") as f:
+        f.write(data)
+
+
+if __name__ == "__main__":
+    main()
@@ -5,7 +5,13 @@ cd "${PROJ_ROOT}"
 PATH_TO_EFI="$1"
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
-cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+python3 scripts/embed_symbols.py ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+# Connect COM2 to GDB (see src/gdb.rs) if GDB_PORT is given
+GDB_SERIAL_ARGS=()
+if [ -n "${GDB_PORT}" ]; then
+  echo "Waiting for GDB to connect to port ${GDB_PORT}..."
+  GDB_SERIAL_ARGS=(-serial tcp::${GDB_PORT},server=on,wait=on)
+fi
 set +e
 mkdir -p log
 qemu-system-x86_64 \
@@ -14,6 +20,7 @@ qemu-system-x86_64 \
   -drive format=raw,file=fat:rw:mnt \
   -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
   -serial chardev:char_com1 \
+  "${GDB_SERIAL_ARGS[@]}" \
   -device isa-debug-exit,iobase=0xf4,iosize=0x01
 RETCODE=$?
 set -e
@@ -1,4 +1,4 @@
-use crate::hpet::HpetRegisters;
+use crate::address_space::phys_to_virt;
 use crate::result::Result;
 use core::mem::size_of;
 
@@ -43,7 +43,8 @@ impl<'a> Iterator for XsdtIterator<'a> {
         } else {
             self.index += 1;
             Some(unsafe {
-                &*(self.table.entry(self.index - 1) as *const SystemDescriptionTableHeader)
+                &*(phys_to_virt(self.table.entry(self.index - 1) as u64)
+                    as *const SystemDescriptionTableHeader)
             })
         }
     }
@@ -116,16 +117,183 @@ impl AcpiTable for AcpiHpetDescriptor {
     type Table = Self;
 }
 impl AcpiHpetDescriptor {
-    pub fn base_address(&self) -> Result<&'static mut HpetRegisters> {
-        unsafe {
-            self.address
-                .address_in_memory_space()
-                .map(|addr| &mut *(addr as *mut HpetRegisters))
-        }
+    /// Physical address of the HPET registers. Use map_mmio_registers() to access them.
+    pub fn base_address(&self) -> Result<u64> {
+        self.address
+            .address_in_memory_space()
+            .map(|addr| addr as u64)
     }
 }
 const _: () = assert!(size_of::<AcpiHpetDescriptor>() == 56);
 
+/// Multiple APIC Description Table (5.2.12)
+#[repr(packed)]
+pub struct Madt {
+    header: SystemDescriptionTableHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+const _: () = assert!(size_of::<Madt>() == 44);
+impl AcpiTable for Madt {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+    type Table = Self;
+}
+impl Madt {
+    /// Physical address of the Local APIC registers of each CPU,
+    /// taking the Local APIC Address Override into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|e| match e {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+    /// PCAT_COMPAT: the system also has dual 8259 PICs
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & 1 != 0
+    }
+    pub fn entries(&self) -> MadtIterator {
+        let entries = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self as *const u8).add(size_of::<Self>()),
+                self.header.length as usize - size_of::<Self>(),
+            )
+        };
+        MadtIterator { entries }
+    }
+    /// APIC IDs of the CPUs that are enabled or can be brought online
+    pub fn apic_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries().filter_map(|e| match e {
+            MadtEntry::LocalApic { apic_id, flags, .. } if is_usable_cpu(flags) => {
+                Some(apic_id as u32)
+            }
+            MadtEntry::LocalX2Apic {
+                x2apic_id, flags, ..
+            } if is_usable_cpu(flags) => Some(x2apic_id),
+            _ => None,
+        })
+    }
+    pub fn num_of_cpus(&self) -> usize {
+        self.apic_ids().count()
+    }
+}
+
+// Local APIC Flags (Table 5.23)
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+const MADT_CPU_ONLINE_CAPABLE: u32 = 1 << 1;
+fn is_usable_cpu(flags: u32) -> bool {
+    flags & (MADT_CPU_ENABLED | MADT_CPU_ONLINE_CAPABLE) != 0
+}
+
+/// Interrupt Controller Structures in MADT (Table 5.21)
+/// flags of the interrupt sources are MPS INTI flags (Table 5.26).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u64,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    NmiSource {
+        flags: u16,
+        gsi: u32,
+    },
+    /// processor_uid = 0xFF means all the processors
+    LocalApicNmi {
+        processor_uid: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    /// processor_uid = 0xFFFFFFFF means all the processors
+    LocalX2ApicNmi {
+        flags: u16,
+        processor_uid: u32,
+        lint: u8,
+    },
+    Other {
+        entry_type: u8,
+    },
+}
+
+pub struct MadtIterator<'a> {
+    entries: &'a [u8],
+}
+impl<'a> Iterator for MadtIterator<'a> {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry_type = *self.entries.first()?;
+        let len = *self.entries.get(1)? as usize;
+        if len < 2 || len > self.entries.len() {
+            return None;
+        }
+        let (e, rest) = self.entries.split_at(len);
+        self.entries = rest;
+        let u16_at = |i: usize| u16::from_le_bytes([e[i], e[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([e[i], e[i + 1], e[i + 2], e[i + 3]]);
+        let u64_at = |i: usize| u32_at(i) as u64 | (u32_at(i + 4) as u64) << 32;
+        // Entries may be extended in later revisions, so only the minimum length is checked.
+        Some(match (entry_type, len) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_uid: e[2],
+                apic_id: e[3],
+                flags: u32_at(4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                id: e[2],
+                address: u32_at(4) as u64,
+                gsi_base: u32_at(8),
+            },
+            (2, 10..) => MadtEntry::InterruptSourceOverride {
+                bus: e[2],
+                source: e[3],
+                gsi: u32_at(4),
+                flags: u16_at(8),
+            },
+            (3, 8..) => MadtEntry::NmiSource {
+                flags: u16_at(2),
+                gsi: u32_at(4),
+            },
+            (4, 6..) => MadtEntry::LocalApicNmi {
+                processor_uid: e[2],
+                flags: u16_at(3),
+                lint: e[5],
+            },
+            (5, 12..) => MadtEntry::LocalApicAddressOverride { address: u64_at(4) },
+            (9, 16..) => MadtEntry::LocalX2Apic {
+                x2apic_id: u32_at(4),
+                flags: u32_at(8),
+                processor_uid: u32_at(12),
+            },
+            (10, 12..) => MadtEntry::LocalX2ApicNmi {
+                flags: u16_at(2),
+                processor_uid: u32_at(4),
+                lint: e[8],
+            },
+            _ => MadtEntry::Other { entry_type },
+        })
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct AcpiRsdpStruct {
@@ -139,10 +307,113 @@ pub struct AcpiRsdpStruct {
 }
 impl AcpiRsdpStruct {
     fn xsdt(&self) -> &Xsdt {
-        unsafe { &*(self.xsdt as *const Xsdt) }
+        unsafe { &*(phys_to_virt(self.xsdt) as *const Xsdt) }
     }
     pub fn hpet(&self) -> Option<&AcpiHpetDescriptor> {
         let xsdt = self.xsdt();
         xsdt.find_table(b"HPET").map(AcpiHpetDescriptor::new)
     }
+    pub fn madt(&self) -> Option<&Madt> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(Madt::SIGNATURE).map(Madt::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn madt_entries_are_parsed() {
+        #[rustfmt::skip]
+        let entries: &[u8] = &[
+            // Local APIC: uid 0, id 0, enabled
+            0, 8, 0, 0, 1, 0, 0, 0,
+            // Local APIC: uid 1, id 2, online capable
+            0, 8, 1, 2, 2, 0, 0, 0,
+            // Local APIC: uid 2, id 3, disabled
+            0, 8, 2, 3, 0, 0, 0, 0,
+            // I/O APIC: id 1 @ 0xFEC00000, GSI base 0
+            1, 12, 1, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0,
+            // Interrupt Source Override: ISA IRQ0 -> GSI2
+            2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+            // NMI Source: GSI 23, level, active low
+            3, 8, 0x0F, 0, 23, 0, 0, 0,
+            // Local APIC NMI: all CPUs, LINT1
+            4, 6, 0xFF, 0, 0, 1,
+            // Local x2APIC: id 0x100, enabled, uid 3
+            9, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0,
+            // Local x2APIC NMI: all CPUs, LINT1
+            10, 12, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0,
+            // Unknown entry
+            0x7F, 4, 0, 0,
+        ];
+        let mut table = [0u8; 256];
+        let len = size_of::<Madt>() + entries.len();
+        table[0..4].copy_from_slice(b"APIC");
+        table[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        table[36..40].copy_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        table[40] = 1;
+        table[size_of::<Madt>()..len].copy_from_slice(entries);
+        let madt = Madt::new(unsafe { &*(table.as_ptr() as *const SystemDescriptionTableHeader) });
+
+        assert_eq!(madt.local_apic_address(), 0xFEE0_0000);
+        assert!(madt.has_legacy_pics());
+        let mut e = madt.entries().skip(3);
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::IoApic {
+                id: 1,
+                address: 0xFEC0_0000,
+                gsi_base: 0
+            })
+        );
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source: 0,
+                gsi: 2,
+                flags: 0
+            })
+        );
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::NmiSource {
+                flags: 0xF,
+                gsi: 23
+            })
+        );
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::LocalApicNmi {
+                processor_uid: 0xFF,
+                flags: 0,
+                lint: 1
+            })
+        );
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::LocalX2Apic {
+                x2apic_id: 0x100,
+                flags: 1,
+                processor_uid: 3
+            })
+        );
+        assert_eq!(
+            e.next(),
+            Some(MadtEntry::LocalX2ApicNmi {
+                flags: 0,
+                processor_uid: 0xFFFF_FFFF,
+                lint: 1
+            })
+        );
+        assert_eq!(e.next(), Some(MadtEntry::Other { entry_type: 0x7F }));
+        assert_eq!(e.next(), None);
+        assert_eq!(madt.num_of_cpus(), 3);
+        let mut ids = madt.apic_ids();
+        assert_eq!(ids.next(), Some(0));
+        assert_eq!(ids.next(), Some(2));
+        assert_eq!(ids.next(), Some(0x100));
+    }
 }
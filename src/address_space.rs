@@ -0,0 +1,230 @@
+//! Virtual address space layout of the kernel
+//!
+//! | Range                                         | Usage                             |
+//! |-----------------------------------------------|-----------------------------------|
+//! | 0x0000_0000_0000_0000 - 0x0000_7FFF_FFFF_FFFF | Identity map (lower half)         |
+//! | 0xFFFF_8000_0000_0000 - 0xFFFF_BFFF_FFFF_FFFF | Direct map of the physical memory |
+//! | 0xFFFF_C000_0000_0000 - 0xFFFF_C0FF_FFFF_FFFF | Kernel heap                       |
+//! | 0xFFFF_D000_0000_0000 - 0xFFFF_D0FF_FFFF_FFFF | MMIO window                       |
+//! | 0xFFFF_E000_0000_0000 - 0xFFFF_E0FF_FFFF_FFFF | Lazily backed regions (VMAs)      |
+//! | 0xFFFF_F000_0000_0000 - 0xFFFF_F0FF_FFFF_FFFF | Kernel stacks with guard pages    |
+//!
+//! The kernel image is loaded by the firmware at a low address and is not
+//! relocatable, and the objects allocated before paging is initialized (e.g.
+//! the firmware stack and the early heap) are also at their physical
+//! addresses. So the lower half is identity-mapped (not executable except
+//! for the kernel image), and it can not be given to user processes until
+//! the kernel stops depending on it. Until init_paging() switches to the
+//! kernel page table, physical addresses are used as they are (phys_to_virt()
+//! is an identity function), which is also the case for the unit tests.
+
+use crate::frame_allocator::alloc_frames;
+use crate::frame_allocator::free_frames;
+use crate::frame_allocator::FRAME_SIZE_2M;
+use crate::frame_allocator::FRAME_SIZE_4K;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::x86::read_cr3;
+use crate::x86::PageAttr;
+use crate::x86::TranslationResult;
+use crate::x86::PML4;
+use core::ops::Range;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+pub const IDENTITY_MAP: Range<u64> = 0x0000_0000_0000_0000..0x0000_8000_0000_0000;
+pub const DIRECT_MAP: Range<u64> = 0xFFFF_8000_0000_0000..0xFFFF_C000_0000_0000;
+pub const KERNEL_HEAP: Range<u64> = 0xFFFF_C000_0000_0000..0xFFFF_C100_0000_0000;
+pub const MMIO_WINDOW: Range<u64> = 0xFFFF_D000_0000_0000..0xFFFF_D100_0000_0000;
+pub const VMA_REGION: Range<u64> = 0xFFFF_E000_0000_0000..0xFFFF_E100_0000_0000;
+pub const STACK_REGION: Range<u64> = 0xFFFF_F000_0000_0000..0xFFFF_F100_0000_0000;
+
+static IS_KERNEL_PAGE_TABLE_ACTIVE: AtomicBool = AtomicBool::new(false);
+static KERNEL_PAGE_TABLE: InterruptSafeMutex<Option<&'static mut PML4>> =
+    InterruptSafeMutex::new(None);
+// Next address in KERNEL_HEAP to be mapped
+static NEXT_HEAP_ADDR: AtomicU64 = AtomicU64::new(KERNEL_HEAP.start);
+
+/// Maps the direct map region of [0, end_of_mem) in the physical address space.
+pub fn create_direct_map(table: &mut PML4, end_of_mem: u64) -> Result<()> {
+    if end_of_mem > DIRECT_MAP.end - DIRECT_MAP.start {
+        return Err("Physical memory is too large for the direct map");
+    }
+    table.create_mapping(
+        DIRECT_MAP.start,
+        DIRECT_MAP.start + end_of_mem,
+        0,
+        PageAttr::ReadWriteNoExecuteKernel,
+    )
+}
+
+/// Registers the page table that was loaded to CR3 by init_paging().
+/// phys_to_virt() returns addresses in the direct map after this.
+pub fn set_kernel_page_table(table: &'static mut PML4) {
+    let mut kernel_page_table = KERNEL_PAGE_TABLE.lock();
+    assert!(kernel_page_table.is_none());
+    *kernel_page_table = Some(table);
+    IS_KERNEL_PAGE_TABLE_ACTIVE.store(true, Ordering::SeqCst);
+}
+pub fn is_kernel_page_table_active() -> bool {
+    IS_KERNEL_PAGE_TABLE_ACTIVE.load(Ordering::SeqCst)
+}
+pub fn with_kernel_page_table<R>(f: impl FnOnce(&mut PML4) -> Result<R>) -> Result<R> {
+    match &mut *KERNEL_PAGE_TABLE.lock() {
+        Some(table) => f(table),
+        None => Err("Kernel page table is not initialized"),
+    }
+}
+
+/// Same as with_kernel_page_table() but returns Err instead of waiting if the table is in use.
+/// Exception handlers should use this since the exception may be raised while it is held.
+pub fn try_with_kernel_page_table<R>(f: impl FnOnce(&mut PML4) -> Result<R>) -> Result<R> {
+    match &mut *KERNEL_PAGE_TABLE.try_lock()? {
+        Some(table) => f(table),
+        None => Err("Kernel page table is not initialized"),
+    }
+}
+
+/// Returns the virtual address to access the given physical address.
+/// Drivers should use this instead of casting the physical address to a pointer.
+pub fn phys_to_virt(phys: u64) -> u64 {
+    if is_kernel_page_table_active() {
+        DIRECT_MAP.start + phys
+    } else {
+        phys
+    }
+}
+pub fn virt_to_phys(virt: u64) -> Result<u64> {
+    if !is_kernel_page_table_active() {
+        return Ok(virt);
+    }
+    if DIRECT_MAP.contains(&virt) {
+        return Ok(virt - DIRECT_MAP.start);
+    }
+    match with_kernel_page_table(|table| table.translate(virt))? {
+        TranslationResult::PageMapped4K { phys }
+        | TranslationResult::PageMapped2M { phys }
+        | TranslationResult::PageMapped1G { phys } => Ok(phys),
+    }
+}
+
+/// Translates virt with the page table in CR3.
+/// This does not take any locks so that it can be used on exceptions
+/// (e.g. to walk the stack or to access the memory from a debugger).
+pub fn translate_in_current_page_table(virt: u64) -> Result<u64> {
+    let table = unsafe { &*(phys_to_virt(read_cr3() as u64) as *const PML4) };
+    match table.translate(virt)? {
+        TranslationResult::PageMapped4K { phys }
+        | TranslationResult::PageMapped2M { phys }
+        | TranslationResult::PageMapped1G { phys } => Ok(phys),
+    }
+}
+
+/// Allocates physical memory for the heap and returns its virtual address.
+/// size should be a multiple of FRAME_SIZE_4K.
+pub fn alloc_heap_region(size: usize) -> Result<u64> {
+    if size % FRAME_SIZE_4K != 0 {
+        return Err("size is not aligned to a frame");
+    }
+    // Use 2MiB pages if possible
+    let align = if size % FRAME_SIZE_2M == 0 {
+        FRAME_SIZE_2M
+    } else {
+        FRAME_SIZE_4K
+    };
+    let num_of_frames = size / FRAME_SIZE_4K;
+    if !is_kernel_page_table_active() {
+        return alloc_frames(num_of_frames, align);
+    }
+    // Keep the heap region aligned to 2MiB as well
+    let virt_size = ((size + FRAME_SIZE_2M - 1) & !(FRAME_SIZE_2M - 1)) as u64;
+    let virt = reserve_heap_range(virt_size)?;
+    let phys = match alloc_frames(num_of_frames, align) {
+        Ok(phys) => phys,
+        Err(e) => {
+            release_heap_range(virt, virt_size);
+            return Err(e);
+        }
+    };
+    let result = with_kernel_page_table(|table| {
+        let end = virt + size as u64;
+        table
+            .create_mapping(virt, end, phys, PageAttr::ReadWriteNoExecuteKernel)
+            .or_else(|e| {
+                // Drop the part that was mapped before the failure
+                table.unmap(virt, end)?;
+                Err(e)
+            })
+    });
+    if let Err(e) = result {
+        free_frames(phys, num_of_frames)?;
+        release_heap_range(virt, virt_size);
+        return Err(e);
+    }
+    Ok(virt)
+}
+/// Takes the range of virt_size bytes from KERNEL_HEAP.
+fn reserve_heap_range(virt_size: u64) -> Result<u64> {
+    NEXT_HEAP_ADDR
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+            (next + virt_size <= KERNEL_HEAP.end).then_some(next + virt_size)
+        })
+        .or(Err("Kernel heap region is exhausted"))
+}
+/// Gives back the range taken by reserve_heap_range() if it is still the last one.
+fn release_heap_range(virt: u64, virt_size: u64) {
+    let _ =
+        NEXT_HEAP_ADDR.compare_exchange(virt + virt_size, virt, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn regions_do_not_overlap() {
+        let regions = [
+            IDENTITY_MAP,
+            DIRECT_MAP,
+            KERNEL_HEAP,
+            MMIO_WINDOW,
+            VMA_REGION,
+            STACK_REGION,
+        ];
+        for (i, a) in regions.iter().enumerate() {
+            for b in &regions[i + 1..] {
+                assert!(a.end <= b.start);
+            }
+        }
+    }
+
+    #[test_case]
+    fn direct_map_translates_to_phys() {
+        let mut table = PML4::new();
+        let end_of_mem = 0x1_0000_0000;
+        create_direct_map(&mut table, end_of_mem).unwrap();
+        let phys = 0x1234_5678;
+        match table.translate(DIRECT_MAP.start + phys) {
+            Ok(TranslationResult::PageMapped1G { phys: p })
+            | Ok(TranslationResult::PageMapped2M { phys: p }) => assert_eq!(p, phys),
+            e => panic!("Unexpected translation: {e:?}"),
+        }
+        assert!(table.translate(DIRECT_MAP.start + end_of_mem).is_err());
+        table
+            .unmap(DIRECT_MAP.start, DIRECT_MAP.start + end_of_mem)
+            .unwrap();
+    }
+
+    #[test_case]
+    fn heap_range_is_reserved_within_the_region() {
+        let next = NEXT_HEAP_ADDR.load(Ordering::SeqCst);
+        let size = KERNEL_HEAP.end - next;
+        assert!(reserve_heap_range(size + FRAME_SIZE_2M as u64).is_err());
+        assert_eq!(NEXT_HEAP_ADDR.load(Ordering::SeqCst), next);
+        let virt = reserve_heap_range(FRAME_SIZE_2M as u64).unwrap();
+        assert_eq!(virt, next);
+        release_heap_range(virt, FRAME_SIZE_2M as u64);
+        assert_eq!(NEXT_HEAP_ADDR.load(Ordering::SeqCst), next);
+    }
+}
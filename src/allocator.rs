@@ -1,19 +1,22 @@
 extern crate alloc;
 
+use crate::address_space::alloc_heap_region;
+use crate::backtrace::Backtrace;
+use crate::backtrace::Frame;
+use crate::frame_allocator::FRAME_SIZE_4K;
+use crate::info;
+use crate::mutex::InterruptSafeMutex;
 use crate::result::Result;
-use crate::uefi::EfiMemoryDescriptor;
-use crate::uefi::EfiMemoryType;
-use crate::uefi::MemoryMapHolder;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use alloc::boxed::Box;
-use core::borrow::BorrowMut;
-use core::cell::RefCell;
 use core::cmp::max;
 use core::fmt;
 use core::mem::size_of;
-use core::ops::DerefMut;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
     1usize
@@ -61,8 +64,11 @@ impl Header {
     fn is_allocated(&self) -> bool {
         self.is_allocated
     }
+    fn addr(&self) -> usize {
+        self as *const Header as usize
+    }
     fn end_addr(&self) -> usize {
-        self as *const Header as usize + self.size
+        self.addr() + self.size
     }
     unsafe fn new_from_addr(addr: usize) -> Box<Header> {
         let header = addr as *mut Header;
@@ -78,6 +84,25 @@ impl Header {
         let header = addr.sub(HEADER_SIZE) as *mut Header;
         Box::from_raw(header)
     }
+    /// Absorbs the following chunks into self as long as
+    /// both self and the next chunk are free and adjacent in memory.
+    ///
+    /// before: |-- self (free) --|-- next (free) --|-- next (free) --|-- next (allocated) --
+    /// after:  |-- self (free) -----------------------------------------|-- next (allocated) --
+    fn merge_with_next_free_chunks(&mut self) {
+        while !self.is_allocated() {
+            match &self.next_header {
+                Some(next) if !next.is_allocated() && next.addr() == self.end_addr() => {
+                    let mut next = self.next_header.take().unwrap();
+                    self.size += next.size;
+                    self.next_header = next.next_header.take();
+                    // next is leaked here since its memory is now a part of self.
+                    Box::leak(next);
+                }
+                _ => break,
+            }
+        }
+    }
     //
     // Note: std::alloc::Layout doc says:
     // > All layouts have an associated size and a power-of-two alignment.
@@ -144,89 +169,520 @@ impl fmt::Debug for Header {
     }
 }
 
+/// Objects freed to a SlabCache are pushed to this list.
+/// The link is stored in the object itself.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Cache of objects of the same size class carved out of
+/// 4KiB pages taken from the first-fit allocator.
+/// Since object_size is a power of 2 and the pages are 4KiB aligned,
+/// each object is naturally aligned to object_size.
+/// Pages are kept in the cache once carved.
+struct SlabCache {
+    object_size: usize,
+    free_list: *mut FreeObject,
+}
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free_list: null_mut(),
+        }
+    }
+    fn alloc(&mut self, allocator: &FirstFitAllocator) -> *mut u8 {
+        if self.free_list.is_null() {
+            self.refill(allocator);
+        }
+        let object = self.free_list;
+        if !object.is_null() {
+            self.free_list = unsafe { (*object).next };
+        }
+        object as *mut u8
+    }
+    fn free(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        unsafe {
+            object.write(FreeObject {
+                next: self.free_list,
+            })
+        };
+        self.free_list = object;
+    }
+    fn refill(&mut self, allocator: &FirstFitAllocator) {
+        let page = allocator.alloc_first_fit(LAYOUT_PAGE_4K);
+        if page.is_null() {
+            return;
+        }
+        // Push in reverse order to hand out the objects from the lower address
+        for ofs in (0..LAYOUT_PAGE_4K.size()).step_by(self.object_size).rev() {
+            self.free(unsafe { page.add(ofs) });
+        }
+    }
+}
+const SLAB_OBJECT_SIZE_MIN: usize = size_of::<FreeObject>();
+const SLAB_OBJECT_SIZE_MAX: usize = 512;
+const NUM_OF_SLAB_CACHES: usize =
+    (SLAB_OBJECT_SIZE_MAX.trailing_zeros() - SLAB_OBJECT_SIZE_MIN.trailing_zeros() + 1) as usize;
+const _: () = assert!(SLAB_OBJECT_SIZE_MIN.count_ones() == 1);
+
+/// Returns the index of the slab cache for the layout,
+/// or None if the layout should be served by the first-fit allocator.
+fn slab_cache_index(layout: Layout) -> Option<usize> {
+    let size = max(max(layout.size(), layout.align()), SLAB_OBJECT_SIZE_MIN);
+    if size > SLAB_OBJECT_SIZE_MAX {
+        return None;
+    }
+    let size = round_up_to_nearest_pow2(size).ok()?;
+    Some((size.trailing_zeros() - SLAB_OBJECT_SIZE_MIN.trailing_zeros()) as usize)
+}
+
+/// Small objects (up to SLAB_OBJECT_SIZE_MAX bytes) are served by
+/// the slab caches, and the others by the first-fit allocator.
+///
+/// The list of headers is guarded by InterruptSafeMutex,
+/// so the allocator can be used from interrupt handlers as well.
+/// Allocating while the lock is held on the same CPU
+/// (e.g. from an exception raised inside the allocator)
+/// panics with the location that took the lock.
 pub struct FirstFitAllocator {
-    first_header: RefCell<Option<Box<Header>>>,
+    first_header: InterruptSafeMutex<Option<Box<Header>>>,
+    slab_caches: InterruptSafeMutex<[SlabCache; NUM_OF_SLAB_CACHES]>,
+    heap_size: AtomicUsize,
+    bytes_allocated: AtomicUsize,
+    num_of_allocs: AtomicUsize,
+    num_of_frees: AtomicUsize,
+    is_leak_tracking_enabled: AtomicBool,
+    has_debug_checks: bool,
 }
 
 #[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
-};
-
-// statis 変数である ALLOCATOR を宣言するには、FirstAllocator がスレッドセーフである必要がある
-// しかし現時点での実装はスレッドセーフでない
-// ただ、現時点での OS には単一スレッドしか存在せず、FirstFitAllocator がスレッドセーフでなくても実害はないため、Sync を実装する
-unsafe impl Sync for FirstFitAllocator {}
+pub static ALLOCATOR: FirstFitAllocator =
+    FirstFitAllocator::new_with_debug_checks(cfg!(feature = "heap_debug"));
 
 unsafe impl GlobalAlloc for FirstFitAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.alloc_with_options(layout)
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.num_of_frees.fetch_add(1, Ordering::SeqCst);
+        self.bytes_allocated
+            .fetch_sub(layout.size(), Ordering::SeqCst);
+        if self.is_leak_tracking_enabled.load(Ordering::SeqCst) {
+            LEAK_TRACKER.lock().remove(ptr as usize);
+        }
+        if self.has_debug_checks {
+            self.dealloc_with_redzones(ptr, layout);
+            return;
+        }
+        if let Some(index) = slab_cache_index(layout) {
+            self.slab_caches.lock()[index].free(ptr);
+            return;
+        }
+        let mut first_header = self.first_header.lock();
         let mut region = Header::from_allocated_region(ptr);
         region.is_allocated = false;
+        let freed_addr = region.addr();
         Box::leak(region);
         // region is leaked here to avoid dropping the free info on the memory.
+        merge_around(&mut first_header, freed_addr);
     }
 }
 
+// The heap grows at least by this size at once
+// to avoid making the list of free regions too long.
+const HEAP_GROWTH_SIZE_MIN: usize = 64 * 1024 * 1024;
+
 impl FirstFitAllocator {
+    pub const fn new() -> Self {
+        Self::new_with_debug_checks(false)
+    }
+    /// If has_debug_checks is true, each allocation is surrounded by redzones
+    /// which are verified on dealloc, and freed memory is filled with POISON_BYTE.
+    /// The slab caches are not used in this mode so that every allocation has a Header.
+    pub const fn new_with_debug_checks(has_debug_checks: bool) -> Self {
+        const EMPTY_SLAB_CACHE: SlabCache = SlabCache::new(0);
+        let mut slab_caches = [EMPTY_SLAB_CACHE; NUM_OF_SLAB_CACHES];
+        let mut i = 0;
+        while i < NUM_OF_SLAB_CACHES {
+            slab_caches[i] = SlabCache::new(SLAB_OBJECT_SIZE_MIN << i);
+            i += 1;
+        }
+        Self {
+            first_header: InterruptSafeMutex::new(None),
+            slab_caches: InterruptSafeMutex::new(slab_caches),
+            heap_size: AtomicUsize::new(0),
+            bytes_allocated: AtomicUsize::new(0),
+            num_of_allocs: AtomicUsize::new(0),
+            num_of_frees: AtomicUsize::new(0),
+            is_leak_tracking_enabled: AtomicBool::new(false),
+            has_debug_checks,
+        }
+    }
     pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
-        let mut header = self.first_header.borrow_mut();
-        let mut header = header.deref_mut();
+        let p = if self.has_debug_checks {
+            self.alloc_with_redzones(layout)
+        } else {
+            match slab_cache_index(layout) {
+                Some(index) => self.slab_caches.lock()[index].alloc(self),
+                None => self.alloc_first_fit(layout),
+            }
+        };
+        if p.is_null() {
+            return p;
+        }
+        self.num_of_allocs.fetch_add(1, Ordering::SeqCst);
+        self.bytes_allocated
+            .fetch_add(layout.size(), Ordering::SeqCst);
+        if self.is_leak_tracking_enabled.load(Ordering::SeqCst) {
+            LEAK_TRACKER.lock().record(LiveAllocation {
+                ptr: p as usize,
+                size: layout.size(),
+                return_addresses: collect_return_addresses(),
+            });
+        }
+        p
+    }
+    fn alloc_with_redzones(&self, layout: Layout) -> *mut u8 {
+        let front_redzone_size = front_redzone_size(layout);
+        let Ok(padded_layout) = Layout::from_size_align(
+            front_redzone_size + layout.size() + REDZONE_SIZE,
+            max(layout.align(), REDZONE_SIZE),
+        ) else {
+            return null_mut();
+        };
+        let start = self.alloc_first_fit(padded_layout);
+        if start.is_null() {
+            return start;
+        }
+        let header = unsafe { &*(start.sub(HEADER_SIZE) as *const Header) };
+        let end_addr = header.end_addr();
+        unsafe {
+            let p = start.add(front_redzone_size);
+            start.write_bytes(REDZONE_BYTE, front_redzone_size);
+            let rear_redzone = p.add(layout.size());
+            rear_redzone.write_bytes(REDZONE_BYTE, end_addr - rear_redzone as usize);
+            p
+        }
+    }
+    fn dealloc_with_redzones(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize - front_redzone_size(layout);
+        let header_addr = start - HEADER_SIZE;
+        let mut first_header = self.first_header.lock();
+        if let Err(e) = check_allocated_chunk(&first_header, ptr, layout) {
+            panic!(
+                "Heap corruption detected: {e} (header: {header_addr:#018X}, size: {:#X})",
+                layout.size()
+            );
+        }
+        let mut region = unsafe { Header::from_allocated_region(start as *mut u8) };
+        let end_addr = region.end_addr();
+        unsafe { (start as *mut u8).write_bytes(POISON_BYTE, end_addr - start) };
+        region.is_allocated = false;
+        let freed_addr = region.addr();
+        Box::leak(region);
+        merge_around(&mut first_header, freed_addr);
+    }
+    pub fn stats(&self) -> HeapStats {
+        let mut bytes_free = 0;
+        let mut largest_free_block = 0;
+        let first_header = self.first_header.lock();
+        let mut header = &*first_header;
+        while let Some(e) = header {
+            if !e.is_allocated() {
+                bytes_free += e.size;
+                largest_free_block = max(largest_free_block, e.size);
+            }
+            header = &e.next_header;
+        }
+        HeapStats {
+            heap_size: self.heap_size.load(Ordering::SeqCst),
+            bytes_allocated: self.bytes_allocated.load(Ordering::SeqCst),
+            bytes_free,
+            largest_free_block,
+            num_of_allocs: self.num_of_allocs.load(Ordering::SeqCst),
+            num_of_frees: self.num_of_frees.load(Ordering::SeqCst),
+        }
+    }
+    /// Allocations made while leak tracking is enabled are recorded with the
+    /// return addresses of the allocating code until they are freed, and can be
+    /// listed with dump_live_allocations(). Location::caller() can not be used
+    /// to find the caller since it points the #[global_allocator] line for
+    /// allocations via Box, Vec and so on.
+    pub fn set_leak_tracking(&self, enabled: bool) {
+        self.is_leak_tracking_enabled
+            .store(enabled, Ordering::SeqCst);
+    }
+    pub fn dump_live_allocations(&self) {
+        let tracker = LEAK_TRACKER.lock();
+        info!(
+            "Live allocations ({} allocations were not tracked since the table was full):",
+            tracker.num_of_untracked
+        );
+        for e in tracker.iter() {
+            info!("  {:#018X} size: {:#X}, allocated from:", e.ptr, e.size);
+            for addr in e.return_addresses.iter().filter(|addr| **addr != 0) {
+                info!("    {}", Frame(*addr));
+            }
+        }
+    }
+    fn alloc_first_fit(&self, layout: Layout) -> *mut u8 {
+        let p = self.alloc_from_free_chunks(layout);
+        if !p.is_null() || self.grow(layout).is_err() {
+            return p;
+        }
+        self.alloc_from_free_chunks(layout)
+    }
+    fn alloc_from_free_chunks(&self, layout: Layout) -> *mut u8 {
+        let mut first_header = self.first_header.lock();
+        let mut header = &mut *first_header;
         loop {
             match header {
-                Some(e) => match e.provide(layout.size(), layout.align()) {
-                    Some(p) => break p,
-                    None => {
-                        header = e.next_header.borrow_mut();
-                        continue;
+                Some(e) => {
+                    e.merge_with_next_free_chunks();
+                    match e.provide(layout.size(), layout.align()) {
+                        Some(p) => break p,
+                        None => {
+                            header = &mut e.next_header;
+                            continue;
+                        }
                     }
-                },
+                }
                 None => {
                     break null_mut::<u8>();
                 }
             }
         }
     }
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-                continue;
-            }
-            self.add_free_from_descriptor(e);
+    /// Takes physical frames from the frame allocator and maps them to the heap region
+    /// so that the heap can provide the given layout.
+    fn grow(&self, layout: Layout) -> Result<()> {
+        // Same estimation as Header::can_provide()
+        let size = max(round_up_to_nearest_pow2(layout.size())?, HEADER_SIZE);
+        let align = max(layout.align(), HEADER_SIZE);
+        let size = max(size + HEADER_SIZE * 2 + align, HEAP_GROWTH_SIZE_MIN);
+        let num_of_frames = (size + FRAME_SIZE_4K - 1) / FRAME_SIZE_4K;
+        let start_addr = alloc_heap_region(num_of_frames * FRAME_SIZE_4K)?;
+        self.add_free_region(start_addr as usize, num_of_frames * FRAME_SIZE_4K);
+        self.heap_size
+            .fetch_add(num_of_frames * FRAME_SIZE_4K, Ordering::SeqCst);
+        Ok(())
+    }
+    fn add_free_region(&self, start_addr: usize, size: usize) {
+        let mut header = unsafe { Header::new_from_addr(start_addr) };
+        header.is_allocated = false;
+        header.size = size;
+        let mut first_header = self.first_header.lock();
+        let mut prev = &mut *first_header;
+        // Keep the headers sorted by address so that
+        // the region can be merged with its neighbours.
+        while prev.as_ref().is_some_and(|e| e.addr() < start_addr) {
+            prev = &mut prev.as_mut().unwrap().next_header;
         }
+        header.next_header = prev.take();
+        *prev = Some(header);
+        merge_around(&mut first_header, start_addr);
     }
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
-        // Make sure the allocator does not include the address 0 as a free area.
-        if start_addr == 0 {
-            start_addr += 4096;
-            size = size.saturating_sub(4096);
+}
+
+// Bytes placed before and after each allocation in the debug mode
+const REDZONE_SIZE: usize = 32;
+const REDZONE_BYTE: u8 = 0xFD;
+// Freed memory is filled with this in the debug mode
+const POISON_BYTE: u8 = 0xDD;
+
+fn front_redzone_size(layout: Layout) -> usize {
+    // Keep the returned pointer aligned to layout.align()
+    max(REDZONE_SIZE, layout.align())
+}
+
+/// Verifies that ptr points to an allocated chunk
+/// in the list and its redzones are not overwritten.
+fn check_allocated_chunk(
+    first_header: &Option<Box<Header>>,
+    ptr: *mut u8,
+    layout: Layout,
+) -> Result<()> {
+    let start = ptr as usize - front_redzone_size(layout);
+    let header_addr = start - HEADER_SIZE;
+    let mut header = first_header;
+    let chunk = loop {
+        match header {
+            Some(e) if e.addr() == header_addr => break e,
+            Some(e) => header = &e.next_header,
+            None => return Err("Freed pointer was not allocated (or already freed and merged)"),
         }
-        if size <= 4096 {
+    };
+    if !chunk.is_allocated() {
+        return Err("Double free");
+    }
+    let rear_redzone_start = ptr as usize + layout.size();
+    if rear_redzone_start > chunk.end_addr() {
+        return Err("Layout is larger than the allocated chunk");
+    }
+    let is_intact = |range: core::ops::Range<usize>| {
+        range
+            .into_iter()
+            .all(|addr| unsafe { *(addr as *const u8) } == REDZONE_BYTE)
+    };
+    if !is_intact(start..ptr as usize) {
+        return Err("Redzone before the allocation was overwritten");
+    }
+    if !is_intact(rear_redzone_start..chunk.end_addr()) {
+        return Err("Redzone after the allocation was overwritten");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes taken from the frame allocator
+    pub heap_size: usize,
+    /// Bytes requested by the live allocations
+    pub bytes_allocated: usize,
+    /// Bytes in the free chunks of the first-fit allocator
+    /// (free objects in the slab caches are not included)
+    pub bytes_free: usize,
+    pub largest_free_block: usize,
+    pub num_of_allocs: usize,
+    pub num_of_frees: usize,
+}
+impl HeapStats {
+    /// 0 if all the free bytes are in one block,
+    /// and gets closer to 100 as the free bytes are split into smaller blocks.
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.bytes_free == 0 {
+            0
+        } else {
+            100 - self.largest_free_block * 100 / self.bytes_free
+        }
+    }
+}
+
+// The first few of them are in the allocator and alloc::alloc
+const NUM_OF_RETURN_ADDRESSES: usize = 8;
+const LEAK_TRACKER_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct LiveAllocation {
+    // 0 means that this entry is not used
+    ptr: usize,
+    size: usize,
+    // Return addresses found by following the frame pointers, which identify the caller
+    return_addresses: [u64; NUM_OF_RETURN_ADDRESSES],
+}
+impl LiveAllocation {
+    const EMPTY: Self = Self {
+        ptr: 0,
+        size: 0,
+        return_addresses: [0; NUM_OF_RETURN_ADDRESSES],
+    };
+}
+
+/// Table of the live allocations. This does not use the heap
+/// since it is updated in the middle of alloc() and dealloc().
+struct LeakTracker {
+    entries: [LiveAllocation; LEAK_TRACKER_CAPACITY],
+    num_of_untracked: usize,
+}
+impl LeakTracker {
+    fn record(&mut self, allocation: LiveAllocation) {
+        match self.entries.iter_mut().find(|e| e.ptr == 0) {
+            Some(e) => *e = allocation,
+            None => self.num_of_untracked += 1,
+        }
+    }
+    fn remove(&mut self, ptr: usize) {
+        if let Some(e) = self.entries.iter_mut().find(|e| e.ptr == ptr) {
+            *e = LiveAllocation::EMPTY;
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = &LiveAllocation> {
+        self.entries.iter().filter(|e| e.ptr != 0)
+    }
+}
+static LEAK_TRACKER: InterruptSafeMutex<LeakTracker> = InterruptSafeMutex::new(LeakTracker {
+    entries: [LiveAllocation::EMPTY; LEAK_TRACKER_CAPACITY],
+    num_of_untracked: 0,
+});
+
+fn collect_return_addresses() -> [u64; NUM_OF_RETURN_ADDRESSES] {
+    let mut return_addresses = [0; NUM_OF_RETURN_ADDRESSES];
+    let backtrace = Backtrace::capture();
+    for (e, addr) in return_addresses.iter_mut().zip(backtrace.frames()) {
+        *e = *addr;
+    }
+    return_addresses
+}
+
+/// Merges the chunk freed at freed_addr with its free neighbours.
+///
+/// Headers in the list are sorted by address,
+/// so the previous chunk in memory is the previous one in the list.
+fn merge_around(first_header: &mut Option<Box<Header>>, freed_addr: usize) {
+    let mut header = first_header;
+    while let Some(e) = header {
+        if e.addr() == freed_addr {
+            e.merge_with_next_free_chunks();
             return;
         }
-        let mut header = unsafe { Header::new_from_addr(start_addr) };
-        header.next_header = None;
-        header.is_allocated = false;
-        header.size = size;
-        let mut first_header = self.first_header.borrow_mut();
-        let prev_last = first_header.replace(header);
-        drop(first_header);
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
-        // It's okay not to be sorted the headers at this point
-        // since all the regions written in memory maps are not contiguous
-        // so that they can't be merged anyway
+        if e.next_header.as_ref().map(|next| next.addr()) == Some(freed_addr) {
+            if e.is_allocated() {
+                if let Some(next) = &mut e.next_header {
+                    next.merge_with_next_free_chunks();
+                }
+            } else {
+                e.merge_with_next_free_chunks();
+            }
+            return;
+        }
+        header = &mut e.next_header;
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::frame_allocator::alloc_frames;
+    use crate::frame_allocator::free_frames;
     use alloc::vec;
+    use alloc::vec::Vec;
+    use core::mem::ManuallyDrop;
+    use core::ops::Deref;
+
+    /// An allocator that serves only the frames taken for it, which makes the
+    /// placement of the chunks predictable. The frames are freed on drop.
+    struct TestHeap {
+        // Headers live in the region, so the allocator itself is never dropped.
+        allocator: ManuallyDrop<FirstFitAllocator>,
+        region: u64,
+        num_of_frames: usize,
+    }
+    impl TestHeap {
+        fn new(size: usize, has_debug_checks: bool) -> Self {
+            let num_of_frames = size / FRAME_SIZE_4K;
+            let region = alloc_frames(num_of_frames, FRAME_SIZE_4K).unwrap();
+            let allocator = FirstFitAllocator::new_with_debug_checks(has_debug_checks);
+            allocator.add_free_region(region as usize, size);
+            Self {
+                allocator: ManuallyDrop::new(allocator),
+                region,
+                num_of_frames,
+            }
+        }
+    }
+    impl Deref for TestHeap {
+        type Target = FirstFitAllocator;
+        fn deref(&self) -> &Self::Target {
+            &self.allocator
+        }
+    }
+    impl Drop for TestHeap {
+        fn drop(&mut self) {
+            free_frames(self.region, self.num_of_frames).unwrap();
+        }
+    }
 
     // 基本的な alloc と free の繰り返しが正しく動作する (Vec を利用)
     #[test_case]
@@ -269,6 +725,112 @@ mod test {
         }
     }
 
+    // 解放した領域が隣接する空き領域と結合され、同じ領域を再び確保できることを確認する
+    #[test_case]
+    fn freed_chunk_is_merged_with_neighbours() {
+        let layout = Layout::from_size_align(4 * 1024 * 1024, 4096).unwrap();
+        let p1 = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p1.is_null());
+        unsafe { ALLOCATOR.dealloc(p1, layout) }
+        let p2 = ALLOCATOR.alloc_with_options(layout);
+        assert_eq!(p1, p2);
+        unsafe { ALLOCATOR.dealloc(p2, layout) }
+    }
+
+    // 隣接する複数の領域をばらばらの順に解放した後、それらをまたぐ大きな領域を確保できることを確認する
+    #[test_case]
+    fn freed_chunks_in_any_order_are_merged() {
+        const SIZE: usize = 4 * 1024 * 1024;
+        let allocator = TestHeap::new(SIZE * 8, false);
+        let layout = Layout::from_size_align(SIZE, 4096).unwrap();
+        let a = allocator.alloc_with_options(layout);
+        let b = allocator.alloc_with_options(layout);
+        let c = allocator.alloc_with_options(layout);
+        assert!(!a.is_null() && !b.is_null() && !c.is_null());
+        // Chunks are carved from the end of a free chunk, so c < b < a.
+        assert!((c as usize) < (b as usize) && (b as usize) < (a as usize));
+        unsafe {
+            allocator.dealloc(b, layout);
+            allocator.dealloc(a, layout);
+            allocator.dealloc(c, layout);
+        }
+        let large_layout = Layout::from_size_align(SIZE * 2, 4096).unwrap();
+        let p = allocator.alloc_with_options(large_layout);
+        assert!(c as usize <= p as usize);
+        assert!(p as usize + SIZE * 2 <= a as usize + SIZE);
+        unsafe { allocator.dealloc(p, large_layout) }
+    }
+
+    // 大きな領域の alloc と free を繰り返してもメモリが枯渇しないことを確認する
+    #[test_case]
+    fn malloc_free_large_layouts_repeatedly() {
+        for i in 0..1000 {
+            let layout = Layout::from_size_align(64 * 1024 * 1024 + i * 4096, 4096).unwrap();
+            let p = ALLOCATOR.alloc_with_options(layout);
+            assert!(!p.is_null());
+            unsafe { ALLOCATOR.dealloc(p, layout) }
+        }
+    }
+
+    // 小さなオブジェクトはサイズクラスごとのスラブから、サイズに揃えて確保されることを確認する
+    #[test_case]
+    fn slab_objects_are_aligned_and_reused() {
+        // ALLOCATOR does not use the slabs with the heap_debug feature.
+        let allocator = TestHeap::new(4 * 1024 * 1024, false);
+        for size in [1, 8, 24, 64, 100, 512] {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            let class = max(
+                round_up_to_nearest_pow2(size).unwrap(),
+                SLAB_OBJECT_SIZE_MIN,
+            );
+            let mut pointers = [null_mut::<u8>(); 100];
+            for e in pointers.iter_mut() {
+                *e = allocator.alloc_with_options(layout);
+                assert!(!e.is_null());
+                assert!(*e as usize % class == 0);
+            }
+            for (i, e) in pointers.iter().enumerate() {
+                assert!(!pointers[i + 1..].contains(e));
+            }
+            let last = pointers[pointers.len() - 1];
+            unsafe { allocator.dealloc(last, layout) }
+            // The object freed last should be handed out first
+            assert_eq!(allocator.alloc_with_options(layout), last);
+            for e in pointers {
+                unsafe { allocator.dealloc(e, layout) }
+            }
+        }
+    }
+
+    // 小さなオブジェクトの alloc と free を繰り返しても、同じスラブのオブジェクトが再利用され、
+    // first-fit のヘッダのリストが変更されないことを確認する
+    #[test_case]
+    fn small_objects_are_reused_without_touching_first_fit_headers() {
+        let allocator = TestHeap::new(4 * 1024 * 1024, false);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let headers = || {
+            let mut headers = Vec::new();
+            let first_header = allocator.first_header.lock();
+            let mut header = &*first_header;
+            while let Some(e) = header {
+                headers.push((e.addr(), e.size, e.is_allocated()));
+                header = &e.next_header;
+            }
+            headers
+        };
+        // The first allocation takes a page for the slab from the first-fit list
+        let p = allocator.alloc_with_options(layout);
+        assert!(!p.is_null());
+        unsafe { allocator.dealloc(p, layout) };
+        let before = headers();
+        for _ in 0..100 {
+            let q = allocator.alloc_with_options(layout);
+            assert_eq!(q, p);
+            unsafe { allocator.dealloc(q, layout) };
+        }
+        assert_eq!(headers(), before);
+    }
+
     // 確保した領域が重複していないことを確認する
     #[test_case]
     fn allocated_objects_have_no_overlap() {
@@ -362,4 +924,110 @@ mod test {
             }
         }
     }
+
+    #[test_case]
+    fn stats_count_allocs_and_frees() {
+        let layout = Layout::from_size_align(1024 * 1024, 4096).unwrap();
+        let before = ALLOCATOR.stats();
+        let p = ALLOCATOR.alloc_with_options(layout);
+        let allocated = ALLOCATOR.stats();
+        assert_eq!(allocated.num_of_allocs, before.num_of_allocs + 1);
+        assert_eq!(
+            allocated.bytes_allocated,
+            before.bytes_allocated + layout.size()
+        );
+        assert!(allocated.bytes_free <= allocated.heap_size);
+        assert!(allocated.largest_free_block <= allocated.bytes_free);
+        assert!(allocated.fragmentation_percent() <= 100);
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        let freed = ALLOCATOR.stats();
+        assert_eq!(freed.num_of_frees, before.num_of_frees + 1);
+        assert_eq!(freed.bytes_allocated, before.bytes_allocated);
+    }
+
+    #[test_case]
+    fn leak_tracking_records_live_allocations() {
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        ALLOCATOR.set_leak_tracking(true);
+        let p = ALLOCATOR.alloc_with_options(layout);
+        let b = Box::new([0u8; 4096]);
+        {
+            let tracker = LEAK_TRACKER.lock();
+            let e = tracker
+                .iter()
+                .find(|e| e.ptr == p as usize)
+                .expect("alloc_with_options() was not tracked");
+            assert_eq!(e.size, 256);
+            assert!(e.return_addresses[0] != 0);
+            let e = tracker
+                .iter()
+                .find(|e| e.ptr == &*b as *const [u8; 4096] as usize)
+                .expect("Box::new() was not tracked");
+            assert_eq!(e.size, 4096);
+            assert!(e.return_addresses[0] != 0);
+        }
+        ALLOCATOR.dump_live_allocations();
+        let bp = &*b as *const [u8; 4096] as usize;
+        drop(b);
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        ALLOCATOR.set_leak_tracking(false);
+        let tracker = LEAK_TRACKER.lock();
+        assert!(tracker.iter().all(|e| e.ptr != p as usize && e.ptr != bp));
+    }
+
+    #[test_case]
+    fn debug_checks_detect_corruption_and_double_free() {
+        let allocator = TestHeap::new(4 * 1024 * 1024, true);
+        for (size, align) in [(1, 1), (24, 8), (100, 64), (4096, 4096)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let p = allocator.alloc_with_options(layout);
+            assert!(!p.is_null());
+            assert!(p as usize % align == 0);
+            assert_eq!(unsafe { *p.sub(1) }, REDZONE_BYTE);
+            assert_eq!(unsafe { *p.add(size) }, REDZONE_BYTE);
+            unsafe { p.write_bytes(0x42, size) };
+            let first_header = allocator.first_header.lock();
+            assert_eq!(check_allocated_chunk(&first_header, p, layout), Ok(()));
+            // Overflow by one byte
+            unsafe { *p.add(size) = 0x42 };
+            assert_eq!(
+                check_allocated_chunk(&first_header, p, layout),
+                Err("Redzone after the allocation was overwritten")
+            );
+            unsafe { *p.add(size) = REDZONE_BYTE };
+            // Underflow by one byte
+            unsafe { *p.sub(1) = 0x42 };
+            assert_eq!(
+                check_allocated_chunk(&first_header, p, layout),
+                Err("Redzone before the allocation was overwritten")
+            );
+            unsafe { *p.sub(1) = REDZONE_BYTE };
+            drop(first_header);
+            unsafe { allocator.dealloc(p, layout) };
+            let first_header = allocator.first_header.lock();
+            assert!(check_allocated_chunk(&first_header, p, layout).is_err());
+            for i in 0..size {
+                assert_eq!(unsafe { *p.add(i) }, POISON_BYTE);
+            }
+        }
+    }
+
+    #[test_case]
+    fn double_free_is_detected_before_merging() {
+        let allocator = TestHeap::new(4 * 1024 * 1024, true);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let a = allocator.alloc_with_options(layout);
+        // b is placed just before a, so a is not merged
+        // into the free chunk before it when a is freed.
+        let b = allocator.alloc_with_options(layout);
+        unsafe { allocator.dealloc(a, layout) };
+        {
+            let first_header = allocator.first_header.lock();
+            assert_eq!(
+                check_allocated_chunk(&first_header, a, layout),
+                Err("Double free")
+            );
+        }
+        unsafe { allocator.dealloc(b, layout) };
+    }
 }
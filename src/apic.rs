@@ -0,0 +1,569 @@
+//! Interrupt controllers: the legacy 8259 PICs, Local APIC and I/O APIC
+//!
+//! The 8259 PICs are remapped away from the exception vectors and masked.
+//! Interrupts are delivered to the CPU by its Local APIC (in x2APIC mode if
+//! supported), and legacy ISA IRQs are routed to it by the I/O APICs that
+//! are described in the ACPI MADT.
+
+use crate::acpi::Madt;
+use crate::acpi::MadtEntry;
+use crate::hpet::global_timestamp;
+use crate::hpet::is_hpet_initialized;
+use crate::info;
+use crate::interrupt::irq_handler;
+use crate::interrupt::register_irq_handler;
+use crate::mmio::map_mmio;
+use crate::mmio::map_mmio_registers;
+use crate::mmio::Mmio;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::is_tsc_deadline_supported;
+use crate::x86::is_x2apic_supported;
+use crate::x86::rdtsc;
+use crate::x86::read_msr;
+use crate::x86::write_io_port_u8;
+use crate::x86::write_msr;
+use crate::x86::InterruptInfo;
+use crate::x86::PAGE_SIZE;
+use core::arch::asm;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+/// First vector that can be used for interrupts from devices (0-31 are exceptions)
+pub const IRQ_VECTOR_BASE: u8 = 0x20;
+/// Vectors that the 8259 PICs use if they ever deliver an interrupt
+const PIC_VECTOR_BASE: u8 = 0xE0;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const MSR_IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+// MSR of a Local APIC register in x2APIC mode (= 0x800 + offset / 16)
+const MSR_X2APIC_BASE: u32 = 0x800;
+
+// Local APIC registers (offset in xAPIC mode)
+const LAPIC_REG_ID: u32 = 0x20;
+const LAPIC_REG_TPR: u32 = 0x80;
+const LAPIC_REG_EOI: u32 = 0xB0;
+const LAPIC_REG_SVR: u32 = 0xF0;
+const LAPIC_REG_LVT_TIMER: u32 = 0x320;
+const LAPIC_REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const LAPIC_REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const LAPIC_REG_TIMER_DIVIDE_CONFIG: u32 = 0x3E0;
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+const LAPIC_LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LAPIC_LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const MSR_IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Vector of the Local APIC timer (see start_local_apic_timer())
+pub const LOCAL_APIC_TIMER_VECTOR: u8 = 0x21;
+// Time to count the Local APIC timer and TSC against HPET
+const LOCAL_APIC_TIMER_CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+// I/O APIC registers (accessed via IOREGSEL / IOWIN)
+const IOAPIC_REG_VER: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION_TABLE: u32 = 0x10;
+const IOAPIC_REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const IOAPIC_REDIRECTION_LEVEL_TRIGGER: u64 = 1 << 15;
+const IOAPIC_REDIRECTION_MASKED: u64 = 1 << 16;
+
+const MAX_IO_APICS: usize = 8;
+const NUM_OF_LEGACY_IRQS: usize = 16;
+
+/// Remaps the 8259 PICs to PIC_VECTOR_BASE and masks all of their IRQs.
+/// The PICs are reprogrammed first since they use the exception vectors by default.
+pub fn disable_legacy_pic() {
+    // ICW1: Initialize, ICW4 will be given
+    write_io_port_u8(PIC1_COMMAND, 0x11);
+    write_io_port_u8(PIC2_COMMAND, 0x11);
+    // ICW2: Vector offsets
+    write_io_port_u8(PIC1_DATA, PIC_VECTOR_BASE);
+    write_io_port_u8(PIC2_DATA, PIC_VECTOR_BASE + 8);
+    // ICW3: The slave is connected to IRQ2 of the master
+    write_io_port_u8(PIC1_DATA, 1 << 2);
+    write_io_port_u8(PIC2_DATA, 2);
+    // ICW4: 8086 mode
+    write_io_port_u8(PIC1_DATA, 0x01);
+    write_io_port_u8(PIC2_DATA, 0x01);
+    // Mask all IRQs
+    write_io_port_u8(PIC1_DATA, 0xFF);
+    write_io_port_u8(PIC2_DATA, 0xFF);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LocalApic {
+    XApic { base: u64 },
+    X2Apic,
+}
+impl LocalApic {
+    fn read(&self, reg: u32) -> u32 {
+        match self {
+            Self::XApic { base } => unsafe { &*((base + reg as u64) as *const Mmio<u32>) }.read(),
+            Self::X2Apic => read_msr(MSR_X2APIC_BASE + reg / 16) as u32,
+        }
+    }
+    fn write(&self, reg: u32, value: u32) {
+        match self {
+            Self::XApic { base } => {
+                unsafe { &mut *((base + reg as u64) as *mut Mmio<u32>) }.write(value)
+            }
+            Self::X2Apic => unsafe { write_msr(MSR_X2APIC_BASE + reg / 16, value as u64) },
+        }
+    }
+    pub fn id(&self) -> u32 {
+        match self {
+            Self::XApic { .. } => self.read(LAPIC_REG_ID) >> 24,
+            Self::X2Apic => self.read(LAPIC_REG_ID),
+        }
+    }
+    pub fn eoi(&self) {
+        self.write(LAPIC_REG_EOI, 0);
+    }
+    /// Encodes self into a non-zero u64 to be stored in LOCAL_APIC.
+    fn to_raw(self) -> u64 {
+        match self {
+            // The base is page-aligned, so it never collides with the value for x2APIC.
+            Self::XApic { base } => base,
+            Self::X2Apic => LOCAL_APIC_RAW_X2APIC,
+        }
+    }
+    fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            0 => None,
+            LOCAL_APIC_RAW_X2APIC => Some(Self::X2Apic),
+            base => Some(Self::XApic { base }),
+        }
+    }
+}
+
+const LOCAL_APIC_RAW_X2APIC: u64 = 1;
+// LocalApic::to_raw() of the Local APIC (0 before it is initialized).
+// This is an atomic rather than a mutex since send_eoi() is called for every interrupt.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the Local APIC of the current CPU, in x2APIC mode if it is supported.
+pub fn init_local_apic() -> Result<LocalApic> {
+    let apic_base = read_msr(MSR_IA32_APIC_BASE);
+    let lapic = if is_x2apic_supported() {
+        // x2APIC mode can only be entered from the enabled xAPIC mode
+        unsafe {
+            write_msr(MSR_IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+            write_msr(
+                MSR_IA32_APIC_BASE,
+                apic_base | APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE,
+            );
+        }
+        LocalApic::X2Apic
+    } else {
+        unsafe { write_msr(MSR_IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE) };
+        let base = map_mmio(apic_base & APIC_BASE_ADDR_MASK, PAGE_SIZE)?;
+        LocalApic::XApic { base }
+    };
+    // Accept all the interrupts and enable the APIC with the spurious vector
+    lapic.write(LAPIC_REG_TPR, 0);
+    lapic.write(LAPIC_REG_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    lapic.write(LAPIC_REG_LVT_TIMER, LAPIC_LVT_MASKED);
+    info!("Local APIC: {lapic:?}, id = {}", lapic.id());
+    LOCAL_APIC.store(lapic.to_raw(), Ordering::SeqCst);
+    Ok(lapic)
+}
+pub fn local_apic() -> Option<LocalApic> {
+    LocalApic::from_raw(LOCAL_APIC.load(Ordering::SeqCst))
+}
+/// Signals the end of the interrupt that is being handled to the Local APIC.
+pub fn send_eoi() {
+    if let Some(lapic) = local_apic() {
+        lapic.eoi();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalApicTimerMode {
+    /// The timer fires when TSC reaches IA32_TSC_DEADLINE, which is re-armed on every interrupt.
+    TscDeadline { tsc_per_period: u64 },
+    /// The timer counts down from the initial count (divided by 16) repeatedly.
+    Periodic { initial_count: u32 },
+}
+
+// TSC cycles per period while the timer is in the TSC-deadline mode (0 otherwise)
+static TSC_DEADLINE_PERIOD: AtomicU64 = AtomicU64::new(0);
+// The Local APIC timer is per-CPU, but only the BSP is running for now.
+static LOCAL_APIC_TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Scales the count measured during the calibration to the given period.
+fn count_per_period(count: u64, measured: Duration, period: Duration) -> u64 {
+    (count as u128 * period.as_nanos() / measured.as_nanos().max(1)) as u64
+}
+
+/// Measures how much the Local APIC timer (divided by 16) and TSC advance
+/// during LOCAL_APIC_TIMER_CALIBRATION_PERIOD, using HPET as the reference.
+fn calibrate_local_apic_timer(lapic: &LocalApic) -> Result<(u64, u64, Duration)> {
+    if !is_hpet_initialized() {
+        return Err("HPET is needed to calibrate the Local APIC timer");
+    }
+    lapic.write(LAPIC_REG_LVT_TIMER, LAPIC_LVT_MASKED);
+    lapic.write(LAPIC_REG_TIMER_DIVIDE_CONFIG, LAPIC_TIMER_DIVIDE_BY_16);
+    let t0 = global_timestamp();
+    let tsc0 = rdtsc();
+    lapic.write(LAPIC_REG_TIMER_INITIAL_COUNT, u32::MAX);
+    let mut elapsed = Duration::ZERO;
+    while elapsed < LOCAL_APIC_TIMER_CALIBRATION_PERIOD {
+        busy_loop_hint();
+        elapsed = global_timestamp() - t0;
+    }
+    let lapic_count = u32::MAX - lapic.read(LAPIC_REG_TIMER_CURRENT_COUNT);
+    let tsc_count = rdtsc() - tsc0;
+    lapic.write(LAPIC_REG_TIMER_INITIAL_COUNT, 0);
+    Ok((lapic_count as u64, tsc_count, elapsed))
+}
+
+/// Starts the Local APIC timer of the current CPU to raise LOCAL_APIC_TIMER_VECTOR every period.
+/// TSC-deadline mode is used if it is supported, and periodic mode otherwise.
+pub fn start_local_apic_timer(period: Duration) -> Result<LocalApicTimerMode> {
+    let lapic = local_apic().ok_or("Local APIC is not initialized")?;
+    let (lapic_count, tsc_count, measured) = calibrate_local_apic_timer(&lapic)?;
+    if irq_handler(LOCAL_APIC_TIMER_VECTOR).is_none() {
+        register_irq_handler(LOCAL_APIC_TIMER_VECTOR, on_local_apic_timer)?;
+    }
+    info!(
+        "Local APIC timer: {} Hz (divided by 16), TSC: {} Hz",
+        count_per_period(lapic_count, measured, Duration::from_secs(1)),
+        count_per_period(tsc_count, measured, Duration::from_secs(1)),
+    );
+    let mode = if is_tsc_deadline_supported() {
+        let tsc_per_period = count_per_period(tsc_count, measured, period).max(1);
+        TSC_DEADLINE_PERIOD.store(tsc_per_period, Ordering::SeqCst);
+        lapic.write(
+            LAPIC_REG_LVT_TIMER,
+            LAPIC_LVT_TIMER_TSC_DEADLINE | LOCAL_APIC_TIMER_VECTOR as u32,
+        );
+        // Make sure that the LVT is written before arming the deadline (SDM 10.5.4.1)
+        unsafe {
+            asm!("mfence");
+            write_msr(MSR_IA32_TSC_DEADLINE, rdtsc() + tsc_per_period);
+        }
+        LocalApicTimerMode::TscDeadline { tsc_per_period }
+    } else {
+        let initial_count: u32 = count_per_period(lapic_count, measured, period)
+            .clamp(1, u32::MAX as u64)
+            .try_into()
+            .or(Err("Local APIC timer count overflow"))?;
+        TSC_DEADLINE_PERIOD.store(0, Ordering::SeqCst);
+        lapic.write(
+            LAPIC_REG_LVT_TIMER,
+            LAPIC_LVT_TIMER_PERIODIC | LOCAL_APIC_TIMER_VECTOR as u32,
+        );
+        lapic.write(LAPIC_REG_TIMER_INITIAL_COUNT, initial_count);
+        LocalApicTimerMode::Periodic { initial_count }
+    };
+    info!("Local APIC timer started: {mode:?}, period = {period:?}");
+    Ok(mode)
+}
+pub fn stop_local_apic_timer() {
+    TSC_DEADLINE_PERIOD.store(0, Ordering::SeqCst);
+    if let Some(lapic) = local_apic() {
+        lapic.write(LAPIC_REG_LVT_TIMER, LAPIC_LVT_MASKED);
+        lapic.write(LAPIC_REG_TIMER_INITIAL_COUNT, 0);
+    }
+}
+fn on_local_apic_timer(_: u8, _: &mut InterruptInfo) {
+    LOCAL_APIC_TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    let tsc_per_period = TSC_DEADLINE_PERIOD.load(Ordering::SeqCst);
+    if tsc_per_period != 0 {
+        // The TSC-deadline mode is one-shot. Arm the next deadline.
+        unsafe { write_msr(MSR_IA32_TSC_DEADLINE, rdtsc() + tsc_per_period) };
+    }
+}
+pub fn local_apic_timer_ticks() -> u64 {
+    LOCAL_APIC_TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+#[repr(C)]
+struct IoApicRegisters {
+    index: Mmio<u32>,
+    _reserved: [u32; 3],
+    data: Mmio<u32>,
+}
+
+struct IoApic {
+    registers: &'static mut IoApicRegisters,
+    gsi_base: u32,
+    num_of_entries: u32,
+}
+impl IoApic {
+    /// # Safety
+    /// address should be the physical address of the I/O APIC registers in MADT.
+    unsafe fn new(address: u64, gsi_base: u32) -> Result<Self> {
+        let mut ioapic = Self {
+            registers: map_mmio_registers(address)?,
+            gsi_base,
+            num_of_entries: 0,
+        };
+        ioapic.num_of_entries = ((ioapic.read(IOAPIC_REG_VER) >> 16) & 0xFF) + 1;
+        for i in 0..ioapic.num_of_entries {
+            ioapic.write_redirection(i, IOAPIC_REDIRECTION_MASKED);
+        }
+        Ok(ioapic)
+    }
+    fn read(&mut self, reg: u32) -> u32 {
+        self.registers.index.write(reg);
+        self.registers.data.read()
+    }
+    fn write(&mut self, reg: u32, value: u32) {
+        self.registers.index.write(reg);
+        self.registers.data.write(value);
+    }
+    fn write_redirection(&mut self, index: u32, entry: u64) {
+        let reg = IOAPIC_REG_REDIRECTION_TABLE + index * 2;
+        // Write the upper half first so that the entry is unmasked with the right destination
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_of_entries).contains(&gsi)
+    }
+}
+
+/// Where an interrupt is connected to the I/O APICs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IrqRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+impl IrqRoute {
+    /// Active high and edge-triggered, which is also the default of ISA IRQs
+    const fn edge(gsi: u32) -> Self {
+        Self {
+            gsi,
+            active_low: false,
+            level_triggered: false,
+        }
+    }
+    /// ISA IRQs are identity-mapped to GSIs unless overridden.
+    const fn identity(irq: u8) -> Self {
+        Self::edge(irq as u32)
+    }
+    /// flags: MPS INTI flags of an Interrupt Source Override (Table 5.26)
+    fn overridden(gsi: u32, flags: u16) -> Self {
+        // Polarity: 0b00 = conforms to the bus (active high for ISA), 0b11 = active low
+        // Trigger mode: 0b00 = conforms to the bus (edge for ISA), 0b11 = level
+        Self {
+            gsi,
+            active_low: flags & 0b11 == 0b11,
+            level_triggered: (flags >> 2) & 0b11 == 0b11,
+        }
+    }
+    fn redirection_entry(&self, vector: u8, dest_apic_id: u8) -> u64 {
+        let mut entry = vector as u64 | (dest_apic_id as u64) << 56;
+        if self.active_low {
+            entry |= IOAPIC_REDIRECTION_ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            entry |= IOAPIC_REDIRECTION_LEVEL_TRIGGER;
+        }
+        entry
+    }
+}
+
+struct IoApicList {
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    legacy_irqs: [IrqRoute; NUM_OF_LEGACY_IRQS],
+}
+impl IoApicList {
+    const fn new() -> Self {
+        const NONE: Option<IoApic> = None;
+        let mut legacy_irqs = [IrqRoute::identity(0); NUM_OF_LEGACY_IRQS];
+        let mut irq = 0;
+        while irq < NUM_OF_LEGACY_IRQS {
+            legacy_irqs[irq] = IrqRoute::identity(irq as u8);
+            irq += 1;
+        }
+        Self {
+            io_apics: [NONE; MAX_IO_APICS],
+            legacy_irqs,
+        }
+    }
+    fn route(&self, irq: u8) -> Result<IrqRoute> {
+        self.legacy_irqs
+            .get(irq as usize)
+            .copied()
+            .ok_or("Not a legacy IRQ")
+    }
+    fn io_apic_for(&mut self, gsi: u32) -> Result<&mut IoApic> {
+        self.io_apics
+            .iter_mut()
+            .flatten()
+            .find(|e| e.handles(gsi))
+            .ok_or("No I/O APIC handles the GSI")
+    }
+    /// Writes entry to the redirection table entry for route.gsi.
+    fn write_redirection(&mut self, route: IrqRoute, entry: u64) -> Result<()> {
+        let ioapic = self.io_apic_for(route.gsi)?;
+        let index = route.gsi - ioapic.gsi_base;
+        ioapic.write_redirection(index, entry);
+        Ok(())
+    }
+}
+
+static IO_APICS: InterruptSafeMutex<IoApicList> = InterruptSafeMutex::new(IoApicList::new());
+
+/// Initializes the I/O APICs described in MADT with all the entries masked.
+pub fn init_io_apics(madt: &Madt) -> Result<()> {
+    let mut list = IO_APICS.lock();
+    for e in madt.entries() {
+        match e {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => {
+                let slot = list
+                    .io_apics
+                    .iter_mut()
+                    .find(|e| e.is_none())
+                    .ok_or("Too many I/O APICs")?;
+                let ioapic = unsafe { IoApic::new(address, gsi_base) }?;
+                info!(
+                    "I/O APIC {id}: {address:#X}, GSI {gsi_base}-{}",
+                    gsi_base + ioapic.num_of_entries - 1
+                );
+                *slot = Some(ioapic);
+            }
+            MadtEntry::InterruptSourceOverride {
+                source, gsi, flags, ..
+            } => {
+                if let Some(route) = list.legacy_irqs.get_mut(source as usize) {
+                    *route = IrqRoute::overridden(gsi, flags);
+                    info!("IRQ{source} is overridden: {route:?}");
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn current_apic_id_as_destination() -> Result<u8> {
+    local_apic()
+        .ok_or("Local APIC is not initialized")?
+        .id()
+        .try_into()
+        .or(Err("APIC ID does not fit in the I/O APIC destination"))
+}
+
+/// Delivers the legacy ISA IRQ to the vector on the current CPU.
+pub fn route_legacy_irq(irq: u8, vector: u8) -> Result<()> {
+    if vector < IRQ_VECTOR_BASE {
+        return Err("Vector is reserved for exceptions");
+    }
+    let dest = current_apic_id_as_destination()?;
+    let mut list = IO_APICS.lock();
+    let route = list.route(irq)?;
+    info!("IRQ{irq} -> GSI{} -> vector {vector:#04X}", route.gsi);
+    list.write_redirection(route, route.redirection_entry(vector, dest))
+}
+pub fn mask_legacy_irq(irq: u8) -> Result<()> {
+    let mut list = IO_APICS.lock();
+    let route = list.route(irq)?;
+    list.write_redirection(route, IOAPIC_REDIRECTION_MASKED)
+}
+/// Delivers the edge-triggered, active high interrupt on the GSI to the vector
+/// on the current CPU. This is for devices that are not on the ISA bus (e.g. HPET).
+pub fn route_gsi(gsi: u32, vector: u8) -> Result<()> {
+    if vector < IRQ_VECTOR_BASE {
+        return Err("Vector is reserved for exceptions");
+    }
+    let dest = current_apic_id_as_destination()?;
+    let route = IrqRoute::edge(gsi);
+    info!("GSI{gsi} -> vector {vector:#04X}");
+    IO_APICS
+        .lock()
+        .write_redirection(route, route.redirection_entry(vector, dest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn local_apic_is_encoded_in_u64() {
+        assert!(LocalApic::from_raw(0).is_none());
+        let raw = LocalApic::X2Apic.to_raw();
+        assert_ne!(raw, 0);
+        assert!(matches!(LocalApic::from_raw(raw), Some(LocalApic::X2Apic)));
+        let raw = LocalApic::XApic {
+            base: 0xFFFF_D000_0000_0000,
+        }
+        .to_raw();
+        assert!(matches!(
+            LocalApic::from_raw(raw),
+            Some(LocalApic::XApic {
+                base: 0xFFFF_D000_0000_0000
+            })
+        ));
+    }
+
+    #[test_case]
+    fn timer_count_is_scaled_to_the_period() {
+        let measured = Duration::from_millis(10);
+        // 1 GHz TSC
+        assert_eq!(
+            count_per_period(10_000_000, measured, Duration::from_millis(1)),
+            1_000_000
+        );
+        assert_eq!(
+            count_per_period(10_000_000, measured, Duration::from_secs(1)),
+            1_000_000_000
+        );
+        assert_eq!(
+            count_per_period(
+                62_500,
+                Duration::from_micros(10_001),
+                Duration::from_millis(10)
+            ),
+            62_493
+        );
+        assert_eq!(
+            count_per_period(1, Duration::ZERO, Duration::from_millis(1)),
+            1_000_000
+        );
+    }
+
+    #[test_case]
+    fn legacy_irqs_follow_source_overrides() {
+        let list = IoApicList::new();
+        assert_eq!(list.route(1), Ok(IrqRoute::identity(1)));
+        assert!(list.route(16).is_err());
+        assert_eq!(
+            IrqRoute::identity(4).redirection_entry(0x24, 3),
+            0x0300_0000_0000_0024
+        );
+        // IRQ0 -> GSI2 as QEMU reports, conforming to the ISA bus
+        let route = IrqRoute::overridden(2, 0);
+        assert_eq!(
+            route,
+            IrqRoute {
+                gsi: 2,
+                ..IrqRoute::identity(0)
+            }
+        );
+        // Level-triggered, active low (e.g. SCI)
+        let route = IrqRoute::overridden(9, 0b1111);
+        assert!(route.active_low && route.level_triggered);
+        assert_eq!(
+            route.redirection_entry(0x29, 0),
+            0x29 | IOAPIC_REDIRECTION_ACTIVE_LOW | IOAPIC_REDIRECTION_LEVEL_TRIGGER
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Stack traces by following the chain of frame pointers
+//!
+//! The kernel is built with -Cforce-frame-pointers (see .cargo/config.toml),
+//! so each stack frame starts with the RBP of the caller followed by the
+//! return address:
+//!
+//! ```text
+//!   [rbp + 8]: return address
+//!   [rbp]    : rbp of the caller
+//! ```
+//!
+//! Addresses in the kernel image are printed as offsets from image_base,
+//! since the firmware can load the image anywhere. Add the ImageBase in the
+//! PE header to an offset to get the address for addr2line. The name of the
+//! function is also printed if the symbol table is embedded (see symbols.rs).
+
+use crate::address_space::translate_in_current_page_table;
+use crate::error;
+use crate::symbols::lookup_symbol;
+use crate::x86::read_rbp;
+use core::fmt;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+const MAX_FRAMES: usize = 32;
+
+static IMAGE_BASE: AtomicU64 = AtomicU64::new(0);
+static IMAGE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the range of the loaded kernel image to print addresses relative to it.
+pub fn set_kernel_image(image_base: u64, image_size: u64) {
+    IMAGE_BASE.store(image_base, Ordering::SeqCst);
+    IMAGE_SIZE.store(image_size, Ordering::SeqCst);
+}
+/// Returns the offset of addr from image_base if addr is in the kernel image.
+pub fn offset_in_kernel_image(addr: u64) -> Option<u64> {
+    let image_base = IMAGE_BASE.load(Ordering::SeqCst);
+    let image_size = IMAGE_SIZE.load(Ordering::SeqCst);
+    (image_base..image_base + image_size)
+        .contains(&addr)
+        .then(|| addr - image_base)
+}
+
+/// RIP and RBP of the fatal exception that is being reported with panic!().
+/// FAULT_RIP is 0 if there is none.
+static FAULT_RIP: AtomicU64 = AtomicU64::new(0);
+static FAULT_RBP: AtomicU64 = AtomicU64::new(0);
+
+/// Makes the next Backtrace::for_panic() start from the faulting instruction.
+pub fn set_fault_frame(rip: u64, rbp: u64) {
+    FAULT_RBP.store(rbp, Ordering::SeqCst);
+    FAULT_RIP.store(rip, Ordering::SeqCst);
+}
+
+/// Checks if addr is mapped in the current page table.
+fn is_mapped(addr: u64) -> bool {
+    translate_in_current_page_table(addr).is_ok()
+}
+
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+impl Backtrace {
+    /// Walks the frames from rbp. rip is added as the first frame if given
+    /// (e.g. the address of the faulting instruction).
+    pub fn from_rbp(rip: Option<u64>, rbp: u64) -> Self {
+        Self::walk(rip, rbp, is_mapped)
+    }
+    /// Captures the frames of the caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_rbp(None, read_rbp())
+    }
+    /// Captures the frames for the panic handler. If the panic reports a fatal
+    /// exception (see set_fault_frame()), the frames are walked from the
+    /// faulting instruction instead of the exception handler.
+    #[inline(always)]
+    pub fn for_panic() -> Self {
+        match FAULT_RIP.swap(0, Ordering::SeqCst) {
+            0 => Self::capture(),
+            rip => Self::from_rbp(Some(rip), FAULT_RBP.load(Ordering::SeqCst)),
+        }
+    }
+    fn walk(rip: Option<u64>, mut rbp: u64, is_valid: impl Fn(u64) -> bool) -> Self {
+        let mut bt = Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        if let Some(rip) = rip {
+            bt.push(rip);
+        }
+        while bt.len < MAX_FRAMES {
+            if rbp == 0 || rbp % 8 != 0 || !is_valid(rbp) || !is_valid(rbp + 8) {
+                break;
+            }
+            let frame = rbp as *const u64;
+            let (next_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+            if return_address == 0 {
+                break;
+            }
+            bt.push(return_address);
+            // Callers' frames are at higher addresses. Stop if the chain is broken.
+            if next_rbp <= rbp {
+                break;
+            }
+            rbp = next_rbp;
+        }
+        bt
+    }
+    fn push(&mut self, addr: u64) {
+        self.frames[self.len] = addr;
+        self.len += 1;
+    }
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+    pub fn print(&self) {
+        error!("Backtrace:");
+        for (i, addr) in self.frames().iter().enumerate() {
+            error!("  #{i:<2} {}", Frame(*addr));
+        }
+    }
+}
+
+/// Shows the address with its offset in the kernel image and the function name.
+pub struct Frame(pub u64);
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018X}", self.0)?;
+        if let Some(offset) = offset_in_kernel_image(self.0) {
+            write!(f, " (image_base + {offset:#X})")?;
+        }
+        if let Some(symbol) = lookup_symbol(self.0) {
+            write!(f, " {symbol}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn frames_are_followed_until_the_chain_ends() {
+        let mut stack = [0u64; 8];
+        let base = stack.as_ptr() as u64;
+        // frame 0 @ stack[0] -> frame 1 @ stack[4] -> frame 2 @ stack[6] (end)
+        stack[0] = base + 4 * 8;
+        stack[1] = 0x1111;
+        stack[4] = base + 6 * 8;
+        stack[5] = 0x2222;
+        stack[6] = 0;
+        stack[7] = 0x3333;
+        let bt = Backtrace::walk(Some(0x1000), base, |_| true);
+        assert_eq!(bt.frames(), &[0x1000, 0x1111, 0x2222, 0x3333]);
+        // A frame that points downwards ends the walk
+        stack[4] = base;
+        let bt = Backtrace::walk(None, base, |_| true);
+        assert_eq!(bt.frames(), &[0x1111, 0x2222]);
+        // Unmapped frames are not read
+        let bt = Backtrace::walk(None, base, |addr| addr < base + 8 * 4);
+        assert_eq!(bt.frames(), &[0x1111]);
+        assert!(Backtrace::walk(None, base + 1, |_| true)
+            .frames()
+            .is_empty());
+    }
+
+    #[test_case]
+    fn panic_backtrace_starts_from_the_fault() {
+        set_fault_frame(0x1234, 0);
+        assert_eq!(Backtrace::for_panic().frames(), &[0x1234]);
+        // The frame is used only once
+        assert_ne!(Backtrace::for_panic().frames().first(), Some(&0x1234));
+    }
+}
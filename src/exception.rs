@@ -0,0 +1,199 @@
+//! Names and error code decoders of the CPU exceptions (vector 0-31)
+//!
+//! See SDM Vol.3 6.15 "Exception and Interrupt Reference".
+
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionName {
+    pub mnemonic: &'static str,
+    pub name: &'static str,
+}
+impl fmt::Display for ExceptionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.mnemonic, self.name)
+    }
+}
+
+const fn e(mnemonic: &'static str, name: &'static str) -> ExceptionName {
+    ExceptionName { mnemonic, name }
+}
+const EXCEPTION_NAMES: [ExceptionName; 32] = [
+    e("#DE", "Divide Error"),
+    e("#DB", "Debug Exception"),
+    e("NMI", "Non-maskable Interrupt"),
+    e("#BP", "Breakpoint"),
+    e("#OF", "Overflow"),
+    e("#BR", "BOUND Range Exceeded"),
+    e("#UD", "Invalid Opcode"),
+    e("#NM", "Device Not Available"),
+    e("#DF", "Double Fault"),
+    e("---", "Coprocessor Segment Overrun"),
+    e("#TS", "Invalid TSS"),
+    e("#NP", "Segment Not Present"),
+    e("#SS", "Stack-Segment Fault"),
+    e("#GP", "General Protection"),
+    e("#PF", "Page Fault"),
+    e("---", "Reserved"),
+    e("#MF", "x87 FPU Floating-Point Error"),
+    e("#AC", "Alignment Check"),
+    e("#MC", "Machine Check"),
+    e("#XM", "SIMD Floating-Point Exception"),
+    e("#VE", "Virtualization Exception"),
+    e("#CP", "Control Protection Exception"),
+    e("---", "Reserved"),
+    e("---", "Reserved"),
+    e("---", "Reserved"),
+    e("---", "Reserved"),
+    e("---", "Reserved"),
+    e("---", "Reserved"),
+    e("#HV", "Hypervisor Injection Exception"),
+    e("#VC", "VMM Communication Exception"),
+    e("#SX", "Security Exception"),
+    e("---", "Reserved"),
+];
+
+/// Returns None if the vector is not an exception.
+pub fn exception_name(vector: usize) -> Option<ExceptionName> {
+    EXCEPTION_NAMES.get(vector).copied()
+}
+
+/// Error code of #TS, #NP, #SS and #GP, which points a segment selector or an IDT entry
+#[derive(Clone, Copy, Debug)]
+pub struct SelectorErrorCode(pub u64);
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let e = self.0;
+        if e == 0 {
+            return write!(f, "{e:#X} (not related to a segment)");
+        }
+        let table = if e & 0b010 != 0 {
+            "IDT"
+        } else if e & 0b100 != 0 {
+            "LDT"
+        } else {
+            "GDT"
+        };
+        write!(f, "{e:#X} ({table}[{:#X}]", (e >> 3) & 0x1FFF)?;
+        if e & 0b001 != 0 {
+            write!(f, ", external event")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Error code of #PF
+#[derive(Clone, Copy, Debug)]
+pub struct PageFaultErrorCode(pub u64);
+impl fmt::Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let e = self.0;
+        write!(
+            f,
+            "A {} mode {} on a {} page, page structures are {}",
+            if e & (1 << 2) != 0 {
+                "user"
+            } else {
+                "supervisor"
+            },
+            if e & (1 << 4) != 0 {
+                "instruction fetch"
+            } else if e & (1 << 6) != 0 {
+                "shadow stack access"
+            } else if e & (1 << 1) != 0 {
+                "data write"
+            } else {
+                "data read"
+            },
+            if e & (1 << 0) != 0 {
+                "present"
+            } else {
+                "non-present"
+            },
+            if e & (1 << 3) != 0 {
+                "invalid"
+            } else {
+                "valid"
+            },
+        )?;
+        if e & (1 << 5) != 0 {
+            write!(f, ", protection key violation")?;
+        }
+        if e & (1 << 7) != 0 {
+            write!(f, ", during HLAT paging")?;
+        }
+        if e & (1 << 15) != 0 {
+            write!(f, ", SGX access-control violation")?;
+        }
+        Ok(())
+    }
+}
+
+/// MXCSR, which tells the cause of #XM
+#[derive(Clone, Copy, Debug)]
+pub struct Mxcsr(pub u32);
+impl fmt::Display for Mxcsr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Exception flags are in bit 0-5 and their masks are in bit 7-12.
+        const EXCEPTIONS: [&str; 6] = [
+            "Invalid Operation",
+            "Denormal",
+            "Divide-by-Zero",
+            "Overflow",
+            "Underflow",
+            "Precision",
+        ];
+        write!(f, "{:#010X}", self.0)?;
+        for (i, name) in EXCEPTIONS.iter().enumerate() {
+            if self.0 & (1 << i) != 0 {
+                let masked = if self.0 & (1 << (i + 7)) != 0 {
+                    " (masked)"
+                } else {
+                    ""
+                };
+                write!(f, ", {name}{masked}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn exceptions_are_decoded() {
+        assert_eq!(
+            format!("{}", exception_name(13).unwrap()),
+            "#GP General Protection"
+        );
+        assert_eq!(exception_name(19).unwrap().mnemonic, "#XM");
+        assert!(exception_name(32).is_none());
+        assert_eq!(
+            format!("{}", SelectorErrorCode(0)),
+            "0x0 (not related to a segment)"
+        );
+        assert_eq!(format!("{}", SelectorErrorCode(0x18)), "0x18 (GDT[0x3])");
+        assert_eq!(
+            format!("{}", SelectorErrorCode(0x0E * 8 + 0b011)),
+            "0x73 (IDT[0xE], external event)"
+        );
+        assert_eq!(format!("{}", SelectorErrorCode(0x2C)), "0x2C (LDT[0x5])");
+        assert_eq!(
+            format!("{}", PageFaultErrorCode(0b0000_0011)),
+            "A supervisor mode data write on a present page, page structures are valid"
+        );
+        assert_eq!(
+            format!("{}", PageFaultErrorCode(1 << 15 | 1 << 5 | 0b1_0100)),
+            "A user mode instruction fetch on a non-present page, page structures are valid, \
+             protection key violation, SGX access-control violation"
+        );
+        assert_eq!(
+            format!("{}", Mxcsr(0x1F80 & !(1 << 9) | 0b100 | 0b1)),
+            "0x00001D85, Invalid Operation (masked), Divide-by-Zero"
+        );
+    }
+}
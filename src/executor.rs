@@ -2,7 +2,9 @@ extern crate alloc;
 use crate::hpet::global_timestamp;
 use crate::info;
 use crate::result::Result;
+use crate::x86::are_interrupts_enabled;
 use crate::x86::busy_loop_hint;
+use crate::x86::hlt;
 use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use core::fmt::Debug;
@@ -89,8 +91,10 @@ impl Executor {
     pub fn run(mut executor: Self) -> ! {
         info!("Executor starts running...");
         loop {
-            let task = executor.task_queue().pop_front();
-            if let Some(mut task) = task {
+            for _ in 0..executor.task_queue().len() {
+                let Some(mut task) = executor.task_queue().pop_front() else {
+                    break;
+                };
                 let waker = no_op_waker();
                 let mut context = Context::from_waker(&waker);
                 match task.poll(&mut context) {
@@ -102,6 +106,11 @@ impl Executor {
                     }
                 }
             }
+            // Tasks are waiting for the time or devices, which will not progress
+            // until the next interrupt (at least the timer tick) comes.
+            if are_interrupts_enabled() {
+                hlt();
+            }
         }
     }
 }
@@ -0,0 +1,277 @@
+//! Physical page frame allocator
+//!
+//! All the CONVENTIONAL_MEMORY regions in the memory map are managed
+//! by a bitmap that has one bit per 4KiB frame (1 = used, 0 = free).
+//! The bitmap itself is placed at the beginning of the first free region
+//! that is large enough to hold it.
+//!
+//! The heap (FirstFitAllocator) also takes its memory from here,
+//! so page tables, stacks and DMA buffers can get page-aligned
+//! physical memory without going through Layout rounding.
+
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use core::cmp::max;
+use core::slice;
+
+pub const FRAME_SIZE_4K: usize = 4096;
+pub const FRAME_SIZE_2M: usize = 2 * 1024 * 1024;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // Physical frame number that corresponds to the bit 0 of the bitmap
+    base_frame: usize,
+    num_of_frames: usize,
+    num_of_usable_frames: usize,
+    num_of_free_frames: usize,
+}
+impl BitmapFrameAllocator {
+    pub fn new(memory_map: &MemoryMapHolder) -> Result<Self> {
+        Self::from_free_regions(
+            memory_map
+                .iter()
+                .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+                .map(|e| {
+                    let start = e.physical_start() as usize;
+                    (start, start + e.number_of_pages() as usize * FRAME_SIZE_4K)
+                }),
+        )
+    }
+    /// regions: (start, end) of each free region in bytes. Both should be 4KiB aligned.
+    fn from_free_regions(regions: impl Iterator<Item = (usize, usize)> + Clone) -> Result<Self> {
+        let base_frame = regions
+            .clone()
+            .map(|(start, _)| start / FRAME_SIZE_4K)
+            .min()
+            .ok_or("No free memory found")?;
+        let end_frame = regions
+            .clone()
+            .map(|(_, end)| end / FRAME_SIZE_4K)
+            .max()
+            .ok_or("No free memory found")?;
+        let num_of_frames = end_frame - base_frame;
+        let bitmap_len = (num_of_frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_frames = (bitmap_len * 8 + FRAME_SIZE_4K - 1) / FRAME_SIZE_4K;
+        let bitmap_addr = regions
+            .clone()
+            .find_map(|(start, end)| {
+                // Make sure the bitmap does not include the address 0.
+                let start = max(start, FRAME_SIZE_4K);
+                (start + bitmap_frames * FRAME_SIZE_4K <= end).then_some(start)
+            })
+            .ok_or("No region is large enough to hold the frame bitmap")?;
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_addr as *mut u64, bitmap_len) };
+        bitmap.fill(u64::MAX);
+        let mut allocator = Self {
+            bitmap,
+            base_frame,
+            num_of_frames,
+            num_of_usable_frames: 0,
+            num_of_free_frames: 0,
+        };
+        for (start, end) in regions {
+            // Make sure the allocator does not include the address 0 as a free area.
+            for frame in max(start / FRAME_SIZE_4K, 1)..end / FRAME_SIZE_4K {
+                allocator.set_used(frame - base_frame, false);
+                allocator.num_of_usable_frames += 1;
+                allocator.num_of_free_frames += 1;
+            }
+        }
+        let bitmap_index = bitmap_addr / FRAME_SIZE_4K - base_frame;
+        for index in bitmap_index..bitmap_index + bitmap_frames {
+            allocator.set_used(index, true);
+        }
+        allocator.num_of_free_frames -= bitmap_frames;
+        Ok(allocator)
+    }
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+    fn set_used(&mut self, index: usize, used: bool) {
+        let word = &mut self.bitmap[index / BITS_PER_WORD];
+        if used {
+            *word |= 1 << (index % BITS_PER_WORD);
+        } else {
+            *word &= !(1 << (index % BITS_PER_WORD));
+        }
+    }
+    /// Returns the bitmap index of the first free run of
+    /// num_of_frames frames whose physical frame number is
+    /// a multiple of align_frames.
+    fn find_free_run(&self, num_of_frames: usize, align_frames: usize) -> Option<usize> {
+        let align_up = |index: usize| {
+            ((self.base_frame + index + align_frames - 1) & !(align_frames - 1)) - self.base_frame
+        };
+        let mut index = align_up(0);
+        while index + num_of_frames <= self.num_of_frames {
+            if self.bitmap[index / BITS_PER_WORD] == u64::MAX {
+                // Skip the frames that are all used at once
+                index = align_up((index / BITS_PER_WORD + 1) * BITS_PER_WORD);
+                continue;
+            }
+            match (index..index + num_of_frames).find(|&i| self.is_used(i)) {
+                Some(used) => index = align_up(used + 1),
+                None => return Some(index),
+            }
+        }
+        None
+    }
+    /// Allocates num_of_frames contiguous frames aligned to align bytes.
+    /// align should be a power of 2 and a multiple of FRAME_SIZE_4K.
+    pub fn alloc_frames(&mut self, num_of_frames: usize, align: usize) -> Result<u64> {
+        if num_of_frames == 0 {
+            return Err("num_of_frames should not be 0");
+        }
+        if align % FRAME_SIZE_4K != 0 || !align.is_power_of_two() {
+            return Err("Invalid align");
+        }
+        let index = self
+            .find_free_run(num_of_frames, align / FRAME_SIZE_4K)
+            .ok_or("Out of physical frames")?;
+        for i in index..index + num_of_frames {
+            self.set_used(i, true);
+        }
+        self.num_of_free_frames -= num_of_frames;
+        Ok(((self.base_frame + index) * FRAME_SIZE_4K) as u64)
+    }
+    pub fn free_frames(&mut self, phys: u64, num_of_frames: usize) -> Result<()> {
+        let phys = phys as usize;
+        if phys % FRAME_SIZE_4K != 0 {
+            return Err("phys is not aligned to a frame");
+        }
+        let index = (phys / FRAME_SIZE_4K)
+            .checked_sub(self.base_frame)
+            .ok_or("phys is out of range")?;
+        if index + num_of_frames > self.num_of_frames {
+            return Err("phys is out of range");
+        }
+        if (index..index + num_of_frames).any(|i| !self.is_used(i)) {
+            return Err("Frame is already free");
+        }
+        for i in index..index + num_of_frames {
+            self.set_used(i, false);
+        }
+        self.num_of_free_frames += num_of_frames;
+        Ok(())
+    }
+    pub fn alloc_4k(&mut self) -> Result<u64> {
+        self.alloc_frames(1, FRAME_SIZE_4K)
+    }
+    pub fn alloc_2m(&mut self) -> Result<u64> {
+        self.alloc_frames(FRAME_SIZE_2M / FRAME_SIZE_4K, FRAME_SIZE_2M)
+    }
+    pub fn free_4k(&mut self, phys: u64) -> Result<()> {
+        self.free_frames(phys, 1)
+    }
+    pub fn free_2m(&mut self, phys: u64) -> Result<()> {
+        if phys as usize % FRAME_SIZE_2M != 0 {
+            return Err("phys is not aligned to 2MiB");
+        }
+        self.free_frames(phys, FRAME_SIZE_2M / FRAME_SIZE_4K)
+    }
+    pub fn num_of_free_frames(&self) -> usize {
+        self.num_of_free_frames
+    }
+    pub fn num_of_used_frames(&self) -> usize {
+        self.num_of_usable_frames - self.num_of_free_frames
+    }
+}
+
+static FRAME_ALLOCATOR: InterruptSafeMutex<Option<BitmapFrameAllocator>> =
+    InterruptSafeMutex::new(None);
+pub fn set_global_frame_allocator(allocator: BitmapFrameAllocator) {
+    assert!(FRAME_ALLOCATOR.lock().is_none());
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+fn with_frame_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> Result<R>) -> Result<R> {
+    match &mut *FRAME_ALLOCATOR.lock() {
+        Some(allocator) => f(allocator),
+        None => Err("Frame allocator is not initialized"),
+    }
+}
+pub fn alloc_frames(num_of_frames: usize, align: usize) -> Result<u64> {
+    with_frame_allocator(|a| a.alloc_frames(num_of_frames, align))
+}
+pub fn free_frames(phys: u64, num_of_frames: usize) -> Result<()> {
+    with_frame_allocator(|a| a.free_frames(phys, num_of_frames))
+}
+pub fn alloc_frame_4k() -> Result<u64> {
+    with_frame_allocator(|a| a.alloc_4k())
+}
+/// Returns Err instead of waiting if the frame allocator is in use
+/// (e.g. by the code that raised the page fault being handled).
+pub fn try_alloc_frame_4k() -> Result<u64> {
+    match &mut *FRAME_ALLOCATOR.try_lock()? {
+        Some(allocator) => allocator.alloc_4k(),
+        None => Err("Frame allocator is not initialized"),
+    }
+}
+pub fn alloc_frame_2m() -> Result<u64> {
+    with_frame_allocator(|a| a.alloc_2m())
+}
+pub fn free_frame_4k(phys: u64) -> Result<()> {
+    with_frame_allocator(|a| a.free_4k(phys))
+}
+pub fn free_frame_2m(phys: u64) -> Result<()> {
+    with_frame_allocator(|a| a.free_2m(phys))
+}
+pub fn num_of_free_frames() -> usize {
+    with_frame_allocator(|a| Ok(a.num_of_free_frames())).unwrap_or(0)
+}
+pub fn num_of_used_frames() -> usize {
+    with_frame_allocator(|a| Ok(a.num_of_used_frames())).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn try_alloc_fails_while_frame_allocator_is_held() {
+        {
+            let _allocator = FRAME_ALLOCATOR.lock();
+            assert!(try_alloc_frame_4k().is_err());
+        }
+        let frame = try_alloc_frame_4k().unwrap();
+        free_frame_4k(frame).unwrap();
+    }
+
+    #[test_case]
+    fn alloc_and_free_4k() {
+        let free = num_of_free_frames();
+        let used = num_of_used_frames();
+        let frames = [(); 8].map(|_| alloc_frame_4k().expect("Failed to alloc a 4KiB frame"));
+        assert_eq!(num_of_free_frames(), free - frames.len());
+        assert_eq!(num_of_used_frames(), used + frames.len());
+        for (i, e) in frames.iter().enumerate() {
+            assert!(*e != 0);
+            assert!(*e as usize % FRAME_SIZE_4K == 0);
+            assert!(!frames[i + 1..].contains(e));
+        }
+        for e in frames {
+            free_frame_4k(e).expect("Failed to free a 4KiB frame");
+        }
+        assert_eq!(num_of_free_frames(), free);
+        assert_eq!(num_of_used_frames(), used);
+    }
+
+    #[test_case]
+    fn alloc_and_free_2m() {
+        let free = num_of_free_frames();
+        let frame = alloc_frame_2m().expect("Failed to alloc a 2MiB frame");
+        assert!(frame as usize % FRAME_SIZE_2M == 0);
+        assert_eq!(num_of_free_frames(), free - FRAME_SIZE_2M / FRAME_SIZE_4K);
+        free_frame_2m(frame).expect("Failed to free a 2MiB frame");
+        assert_eq!(num_of_free_frames(), free);
+    }
+
+    #[test_case]
+    fn double_free_is_rejected() {
+        let frame = alloc_frame_4k().expect("Failed to alloc a 4KiB frame");
+        assert_eq!(free_frame_4k(frame), Ok(()));
+        assert_eq!(free_frame_4k(frame), Err("Frame is already free"));
+    }
+}
@@ -0,0 +1,756 @@
+//! Stub of the GDB Remote Serial Protocol on COM2
+//!
+//! The stub takes over the breakpoint (#BP) and debug (#DB) exceptions and
+//! talks to GDB until it resumes the kernel. GDB can also stop the kernel
+//! with Ctrl-C, which is delivered as the receive interrupt of COM2.
+//!
+//! ```text
+//! $ GDB_PORT=1234 cargo run
+//! $ gdb -ex 'set architecture i386:x86-64' -ex 'target remote :1234'
+//! ```
+//!
+//! The image has no DWARF, so use the addresses printed in backtraces
+//! (image_base + offset) to set breakpoints.
+//! c.f. https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+use crate::address_space::phys_to_virt;
+use crate::address_space::translate_in_current_page_table;
+use crate::apic::mask_legacy_irq;
+use crate::apic::route_legacy_irq;
+use crate::interrupt::allocate_irq_vector;
+use crate::interrupt::register_irq_handler;
+use crate::interrupt::unregister_irq_handler;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::serial::SerialPort;
+use crate::watchpoint::report_watchpoint_hit;
+use crate::x86::busy_loop_hint;
+use crate::x86::InterruptInfo;
+use crate::x86::Register;
+use crate::x86::RFLAGS_TF;
+
+const COM2_IRQ: u8 = 3;
+const MAX_PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+/// Sent by GDB to stop the target (Ctrl-C)
+const INTERRUPT_REQUEST: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+/// EFAULT, for memory accesses to unmapped addresses
+const ERROR_FAULT: &[u8] = b"E0e";
+const ERROR_INVALID: &[u8] = b"E16";
+
+/// Registers in the order of the amd64 target of GDB.
+/// rax-rip are 8 bytes long and the others are 4 bytes long.
+const GDB_REGISTERS: [Register; 20] = [
+    Register::Rax,
+    Register::Rbx,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rsi,
+    Register::Rdi,
+    Register::Rbp,
+    Register::Rsp,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+    Register::Rip,
+    Register::Rflags,
+    Register::Cs,
+    Register::Ss,
+];
+fn gdb_register_size(reg: Register) -> usize {
+    match reg {
+        Register::Rflags | Register::Cs | Register::Ss => 4,
+        _ => 8,
+    }
+}
+
+trait Connection {
+    fn read_u8(&mut self) -> u8;
+    fn write_u8(&mut self, v: u8);
+}
+impl Connection for SerialPort {
+    fn read_u8(&mut self) -> u8 {
+        loop {
+            if let Some(v) = self.try_read() {
+                return v;
+            }
+            busy_loop_hint();
+        }
+    }
+    fn write_u8(&mut self, v: u8) {
+        self.send_char(v as char)
+    }
+}
+
+trait TargetMemory {
+    fn read_u8(&self, addr: u64) -> Result<u8>;
+    fn write_u8(&mut self, addr: u64, v: u8) -> Result<()>;
+}
+/// Accesses the memory through the current page table, without faulting on unmapped pages.
+struct KernelMemory;
+impl KernelMemory {
+    fn ptr(addr: u64) -> Result<*mut u8> {
+        // Go through the direct map, which is writable even if addr is in a
+        // read-only page (e.g. breakpoints in .text).
+        Ok(phys_to_virt(translate_in_current_page_table(addr)?) as *mut u8)
+    }
+}
+impl TargetMemory for KernelMemory {
+    fn read_u8(&self, addr: u64) -> Result<u8> {
+        Ok(unsafe { Self::ptr(addr)?.read_volatile() })
+    }
+    fn write_u8(&mut self, addr: u64, v: u8) -> Result<()> {
+        unsafe { Self::ptr(addr)?.write_volatile(v) };
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StopReason {
+    /// int3 is executed
+    Breakpoint,
+    /// A single step is done or a watchpoint is hit
+    Debug,
+    /// GDB requested to stop
+    Interrupt,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|v| v as u8)
+}
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |acc, &c| Some(acc << 4 | hex_digit(c)? as u64))
+}
+fn parse_hex_bytes(s: &[u8]) -> impl Iterator<Item = Option<u8>> + '_ {
+    s.chunks(2).map(|c| match c {
+        [hi, lo] => Some(hex_digit(*hi)? << 4 | hex_digit(*lo)?),
+        _ => None,
+    })
+}
+/// Splits "addr,len" (or "addr,kind" of breakpoints)
+fn parse_addr_len(s: &[u8]) -> Option<(u64, u64)> {
+    let mut it = s.splitn(2, |&c| c == b',');
+    Some((parse_hex(it.next()?)?, parse_hex(it.next()?)?))
+}
+
+struct Reply {
+    data: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+impl Reply {
+    const fn new() -> Self {
+        Self {
+            data: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+    /// Data that does not fit in a packet is dropped.
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+    fn push_hex(&mut self, bytes: &[u8]) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for b in bytes {
+            self.push(&[DIGITS[(b >> 4) as usize], DIGITS[(b & 0xF) as usize]]);
+        }
+    }
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+type ReplyResult = core::result::Result<(), &'static [u8]>;
+
+enum Action {
+    Reply,
+    Resume,
+    ReplyAndResume,
+}
+
+struct GdbStub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// GDB expects the PC to be rewound to the breakpoint if "swbreak" is reported.
+    is_swbreak_supported: bool,
+    reply: Reply,
+}
+impl GdbStub {
+    const fn new() -> Self {
+        Self {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            is_swbreak_supported: false,
+            reply: Reply::new(),
+        }
+    }
+    /// Talks to GDB until it resumes the execution.
+    fn run(
+        &mut self,
+        conn: &mut impl Connection,
+        mem: &mut impl TargetMemory,
+        info: &mut InterruptInfo,
+        reason: StopReason,
+    ) {
+        // Without swbreak, GDB rewinds the PC by itself (decr_pc_after_break on x86).
+        let is_swbreak = reason == StopReason::Breakpoint
+            && self.is_swbreak_supported
+            && self.rewind_to_breakpoint(info);
+        self.reply.clear();
+        self.push_stop_reply(reason, is_swbreak);
+        self.send_reply(conn);
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let len = receive_packet(conn, &mut packet);
+            self.reply.clear();
+            match self.handle_packet(&packet[..len], mem, info, reason) {
+                Action::Reply => self.send_reply(conn),
+                Action::Resume => return,
+                Action::ReplyAndResume => {
+                    self.send_reply(conn);
+                    return;
+                }
+            }
+        }
+    }
+    /// Moves RIP back to the int3 if it is one of the breakpoints set by GDB.
+    fn rewind_to_breakpoint(&self, info: &mut InterruptInfo) -> bool {
+        let rip = info.register_mut(Register::Rip);
+        let addr = rip.wrapping_sub(1);
+        let is_inserted = self.breakpoints.iter().flatten().any(|bp| bp.addr == addr);
+        if is_inserted {
+            *rip = addr;
+        }
+        is_inserted
+    }
+    fn push_stop_reply(&mut self, reason: StopReason, is_swbreak: bool) {
+        let signal = if reason == StopReason::Interrupt {
+            SIGINT
+        } else {
+            SIGTRAP
+        };
+        if is_swbreak {
+            self.reply.push(b"T");
+            self.reply.push_hex(&[signal]);
+            self.reply.push(b"swbreak:;");
+        } else {
+            self.reply.push(b"S");
+            self.reply.push_hex(&[signal]);
+        }
+    }
+    /// Sends the reply until GDB acknowledges it.
+    fn send_reply(&self, conn: &mut impl Connection) {
+        let data = self.reply.as_bytes();
+        let sum = data.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+        let mut checksum = Reply::new();
+        checksum.push_hex(&[sum]);
+        loop {
+            conn.write_u8(b'$');
+            for &c in data {
+                conn.write_u8(c);
+            }
+            conn.write_u8(b'#');
+            for &c in checksum.as_bytes() {
+                conn.write_u8(c);
+            }
+            loop {
+                match conn.read_u8() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    fn handle_packet(
+        &mut self,
+        packet: &[u8],
+        mem: &mut impl TargetMemory,
+        info: &mut InterruptInfo,
+        reason: StopReason,
+    ) -> Action {
+        let (&command, args) = match packet.split_first() {
+            Some(v) => v,
+            None => return Action::Reply,
+        };
+        let result: ReplyResult = match command {
+            b'?' => {
+                self.push_stop_reply(reason, false);
+                Ok(())
+            }
+            b'g' => {
+                for reg in GDB_REGISTERS {
+                    let value = *info.register_mut(reg);
+                    self.reply
+                        .push_hex(&value.to_le_bytes()[..gdb_register_size(reg)]);
+                }
+                Ok(())
+            }
+            b'G' => write_registers(info, args)
+                .ok_or(ERROR_INVALID)
+                .map(|()| self.reply.push(b"OK")),
+            b'p' => match parse_hex(args).and_then(|i| GDB_REGISTERS.get(i as usize)) {
+                Some(&reg) => {
+                    let value = *info.register_mut(reg);
+                    self.reply
+                        .push_hex(&value.to_le_bytes()[..gdb_register_size(reg)]);
+                    Ok(())
+                }
+                None => Err(ERROR_INVALID),
+            },
+            b'P' => write_register(info, args)
+                .ok_or(ERROR_INVALID)
+                .map(|()| self.reply.push(b"OK")),
+            b'm' => match parse_addr_len(args) {
+                Some((addr, len)) => {
+                    let len = len.min((MAX_PACKET_SIZE / 2) as u64);
+                    let mut result = Err(ERROR_FAULT);
+                    // Returns the bytes before the first unmapped one
+                    for addr in addr..addr.saturating_add(len) {
+                        match mem.read_u8(addr) {
+                            Ok(v) => {
+                                self.reply.push_hex(&[v]);
+                                result = Ok(());
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    result
+                }
+                None => Err(ERROR_INVALID),
+            },
+            b'M' => write_memory(mem, args).map(|()| self.reply.push(b"OK")),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => *info.register_mut(Register::Rip) = addr,
+                        None => return self.error(ERROR_INVALID),
+                    }
+                }
+                let rflags = info.register_mut(Register::Rflags);
+                if command == b's' {
+                    *rflags |= RFLAGS_TF;
+                } else {
+                    *rflags &= !RFLAGS_TF;
+                }
+                return Action::Resume;
+            }
+            b'Z' | b'z' => match args.split_first() {
+                Some((b'0', args)) => match args.strip_prefix(b",").and_then(parse_addr_len) {
+                    Some((addr, _)) if command == b'Z' => self.insert_breakpoint(mem, addr),
+                    Some((addr, _)) => self.remove_breakpoint(mem, addr),
+                    None => Err(ERROR_INVALID),
+                },
+                // Other types of breakpoints are not supported (empty reply).
+                _ => return Action::Reply,
+            },
+            b'D' | b'k' => {
+                self.remove_all_breakpoints(mem);
+                *info.register_mut(Register::Rflags) &= !RFLAGS_TF;
+                if command == b'k' {
+                    // GDB does not wait for the reply of k
+                    return Action::Resume;
+                }
+                self.reply.push(b"OK");
+                return Action::ReplyAndResume;
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                self.is_swbreak_supported = args.windows(8).any(|w| w == b"swbreak+");
+                self.reply.push(b"PacketSize=");
+                self.reply.push_hex(&(MAX_PACKET_SIZE as u16).to_be_bytes());
+                if self.is_swbreak_supported {
+                    self.reply.push(b";swbreak+");
+                }
+                Ok(())
+            }
+            b'q' if args == b"Attached" => {
+                self.reply.push(b"1");
+                Ok(())
+            }
+            // There is only one thread.
+            b'H' | b'T' => {
+                self.reply.push(b"OK");
+                Ok(())
+            }
+            // Not supported (empty reply)
+            _ => Ok(()),
+        };
+        match result {
+            Ok(()) => Action::Reply,
+            Err(e) => self.error(e),
+        }
+    }
+    fn error(&mut self, e: &[u8]) -> Action {
+        self.reply.clear();
+        self.reply.push(e);
+        Action::Reply
+    }
+    fn insert_breakpoint(&mut self, mem: &mut impl TargetMemory, addr: u64) -> ReplyResult {
+        if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            self.reply.push(b"OK");
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.is_none())
+            .ok_or(ERROR_INVALID)?;
+        let original = mem.read_u8(addr).or(Err(ERROR_FAULT))?;
+        mem.write_u8(addr, INT3).or(Err(ERROR_FAULT))?;
+        *slot = Some(Breakpoint { addr, original });
+        self.reply.push(b"OK");
+        Ok(())
+    }
+    fn remove_breakpoint(&mut self, mem: &mut impl TargetMemory, addr: u64) -> ReplyResult {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.is_some_and(|bp| bp.addr == addr))
+            .ok_or(ERROR_INVALID)?;
+        if let Some(bp) = slot.take() {
+            mem.write_u8(bp.addr, bp.original).or(Err(ERROR_FAULT))?;
+        }
+        self.reply.push(b"OK");
+        Ok(())
+    }
+    fn remove_all_breakpoints(&mut self, mem: &mut impl TargetMemory) {
+        for bp in self.breakpoints.iter_mut().filter_map(|bp| bp.take()) {
+            let _ = mem.write_u8(bp.addr, bp.original);
+        }
+    }
+}
+
+/// Receives "$data#checksum" and acknowledges it. Returns the length of data.
+fn receive_packet(conn: &mut impl Connection, packet: &mut [u8]) -> usize {
+    loop {
+        // Acks and interrupt requests are ignored while the target is stopped.
+        while conn.read_u8() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut is_too_long = false;
+        loop {
+            let c = conn.read_u8();
+            if c == b'#' {
+                break;
+            }
+            match packet.get_mut(len) {
+                Some(slot) => {
+                    *slot = c;
+                    len += 1;
+                }
+                None => is_too_long = true,
+            }
+            sum = sum.wrapping_add(c);
+        }
+        let checksum = parse_hex(&[conn.read_u8(), conn.read_u8()]);
+        if checksum == Some(sum as u64) && !is_too_long {
+            conn.write_u8(b'+');
+            return len;
+        }
+        conn.write_u8(b'-');
+    }
+}
+
+/// Handles "G" with the values of the registers in GDB_REGISTERS.
+fn write_registers(info: &mut InterruptInfo, args: &[u8]) -> Option<()> {
+    let mut args = args;
+    for reg in GDB_REGISTERS {
+        if args.is_empty() {
+            break;
+        }
+        let size = gdb_register_size(reg);
+        let (value, rest) = (args.get(..size * 2)?, &args[size * 2..]);
+        set_register(info, reg, value)?;
+        args = rest;
+    }
+    Some(())
+}
+/// Handles "Pn=value"
+fn write_register(info: &mut InterruptInfo, args: &[u8]) -> Option<()> {
+    let mut it = args.splitn(2, |&c| c == b'=');
+    let index = parse_hex(it.next()?)?;
+    let reg = *GDB_REGISTERS.get(index as usize)?;
+    set_register(info, reg, it.next()?)
+}
+fn set_register(info: &mut InterruptInfo, reg: Register, hex: &[u8]) -> Option<()> {
+    let mut bytes = [0u8; 8];
+    for (i, b) in parse_hex_bytes(hex).enumerate() {
+        *bytes.get_mut(i)? = b?;
+    }
+    let value = u64::from_le_bytes(bytes);
+    let current = info.register_mut(reg);
+    if matches!(reg, Register::Cs | Register::Ss) {
+        // Changing the segments would break the return from the exception.
+        return (*current == value).then_some(());
+    }
+    *current = value;
+    Some(())
+}
+/// Handles "Maddr,len:data"
+fn write_memory(mem: &mut impl TargetMemory, args: &[u8]) -> ReplyResult {
+    let mut it = args.splitn(2, |&c| c == b':');
+    let (addr, len) = it.next().and_then(parse_addr_len).ok_or(ERROR_INVALID)?;
+    let data = it.next().ok_or(ERROR_INVALID)?;
+    if data.len() as u64 != len * 2 {
+        return Err(ERROR_INVALID);
+    }
+    for (addr, v) in (addr..).zip(parse_hex_bytes(data)) {
+        mem.write_u8(addr, v.ok_or(ERROR_INVALID)?)
+            .or(Err(ERROR_FAULT))?;
+    }
+    Ok(())
+}
+
+static GDB_STUB: InterruptSafeMutex<GdbStub> = InterruptSafeMutex::new(GdbStub::new());
+
+fn enter_gdb_stub(info: &mut InterruptInfo, reason: StopReason) {
+    let mut port = SerialPort::new_for_com2();
+    GDB_STUB
+        .lock()
+        .run(&mut port, &mut KernelMemory, info, reason)
+}
+fn on_debug_exception(_: u8, info: &mut InterruptInfo) {
+    // Watchpoints are reported before GDB takes over (and DR6 is cleared).
+    report_watchpoint_hit(info);
+    enter_gdb_stub(info, StopReason::Debug)
+}
+fn on_breakpoint(_: u8, info: &mut InterruptInfo) {
+    enter_gdb_stub(info, StopReason::Breakpoint)
+}
+fn on_com2_interrupt(_: u8, info: &mut InterruptInfo) {
+    let port = SerialPort::new_for_com2();
+    let mut is_interrupted = false;
+    while let Some(c) = port.try_read() {
+        is_interrupted |= c == INTERRUPT_REQUEST;
+    }
+    if is_interrupted {
+        enter_gdb_stub(info, StopReason::Interrupt)
+    }
+}
+
+/// Starts the stub if COM2 exists. This should be called after the interrupt
+/// controllers are initialized. Use trigger_debug_interrupt() to wait for GDB.
+pub fn init_gdb_stub() -> Result<()> {
+    let mut port = SerialPort::new_for_com2();
+    port.init();
+    port.loopback_test().or(Err("COM2 is not available"))?;
+    let vector = allocate_irq_vector(on_com2_interrupt)?;
+    // #DB and #BP are hooked last so that they can be left as they were on failure
+    let result = route_legacy_irq(COM2_IRQ, vector).and_then(|()| {
+        register_irq_handler(1, on_debug_exception)?;
+        register_irq_handler(3, on_breakpoint).or_else(|e| {
+            unregister_irq_handler(1)?;
+            Err(e)
+        })
+    });
+    if let Err(e) = result {
+        // This fails as well if the IRQ was not routed
+        let _ = mask_legacy_irq(COM2_IRQ);
+        unregister_irq_handler(vector)?;
+        return Err(e);
+    }
+    port.enable_receive_interrupt();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    struct FakeConnection {
+        input: Vec<u8>,
+        pos: usize,
+        output: Vec<u8>,
+    }
+    impl Connection for FakeConnection {
+        fn read_u8(&mut self) -> u8 {
+            let v = self.input[self.pos];
+            self.pos += 1;
+            v
+        }
+        fn write_u8(&mut self, v: u8) {
+            self.output.push(v)
+        }
+    }
+    /// 16 bytes mapped at 0x1000
+    struct FakeMemory([u8; 16]);
+    impl TargetMemory for FakeMemory {
+        fn read_u8(&self, addr: u64) -> Result<u8> {
+            let i = addr.checked_sub(0x1000).ok_or("unmapped")? as usize;
+            self.0.get(i).copied().ok_or("unmapped")
+        }
+        fn write_u8(&mut self, addr: u64, v: u8) -> Result<()> {
+            let i = addr.checked_sub(0x1000).ok_or("unmapped")? as usize;
+            *self.0.get_mut(i).ok_or("unmapped")? = v;
+            Ok(())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+        format!("${data}#{sum:02x}")
+    }
+    /// Runs a session with the commands (each of them is acked by the fake GDB)
+    /// and returns the replies (without the stop reply).
+    fn run_session(
+        stub: &mut GdbStub,
+        mem: &mut FakeMemory,
+        info: &mut InterruptInfo,
+        reason: StopReason,
+        commands: &[&str],
+    ) -> Vec<String> {
+        let mut input = String::from("+");
+        for c in commands {
+            input += &packet(c);
+            input += "+";
+        }
+        let mut conn = FakeConnection {
+            input: input.into_bytes(),
+            pos: 0,
+            output: Vec::new(),
+        };
+        stub.run(&mut conn, mem, info, reason);
+        let output = String::from_utf8(conn.output).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|p| String::from(p.split('#').next().unwrap()))
+            .collect()
+    }
+
+    #[test_case]
+    fn packets_are_handled() {
+        let mut stub = GdbStub::new();
+        let mut mem = FakeMemory([0x90; 16]);
+        let mut info: InterruptInfo = unsafe { core::mem::zeroed() };
+        *info.register_mut(Register::Rax) = 0x1122_3344_5566_7788;
+        *info.register_mut(Register::Rip) = 0x1000;
+        *info.register_mut(Register::Cs) = 0x08;
+        let replies = run_session(
+            &mut stub,
+            &mut mem,
+            &mut info,
+            StopReason::Debug,
+            &[
+                "qSupported:multiprocess+;swbreak+",
+                "p0",
+                "P10=0410000000000000",
+                "P12=10000000",
+                "m1000,4",
+                "m100e,4",
+                "m2000,1",
+                "M1000,2:cafe",
+                "Z0,1008,1",
+                "s",
+            ],
+        );
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "PacketSize=1000;swbreak+");
+        assert_eq!(replies[2], "8877665544332211");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(*info.register_mut(Register::Rip), 0x1004);
+        // cs can not be changed
+        assert_eq!(replies[4], "E16");
+        assert_eq!(replies[5], "90909090");
+        // Bytes before the unmapped one are returned
+        assert_eq!(replies[6], "9090");
+        assert_eq!(replies[7], "E0e");
+        assert_eq!(replies[8], "OK");
+        assert_eq!(&mem.0[..3], &[0xCA, 0xFE, 0x90]);
+        assert_eq!(replies[9], "OK");
+        assert_eq!(mem.0[8], INT3);
+        assert_eq!(replies.len(), 10);
+        assert!(*info.register_mut(Register::Rflags) & RFLAGS_TF != 0);
+
+        // Hit the breakpoint: RIP is rewound and reported as swbreak
+        *info.register_mut(Register::Rip) = 0x1009;
+        let replies = run_session(
+            &mut stub,
+            &mut mem,
+            &mut info,
+            StopReason::Breakpoint,
+            &["g", "z0,1008,1", "z0,1008,1", "c"],
+        );
+        assert_eq!(replies[0], "T05swbreak:;");
+        let regs = &replies[1];
+        assert_eq!(regs.len(), 17 * 16 + 3 * 8);
+        assert_eq!(&regs[..16], "8877665544332211");
+        assert_eq!(&regs[16 * 16..17 * 16], "0810000000000000");
+        assert_eq!(&regs[17 * 16 + 8..17 * 16 + 16], "08000000");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(mem.0[8], 0x90);
+        assert_eq!(replies[3], "E16");
+        assert_eq!(*info.register_mut(Register::Rflags) & RFLAGS_TF, 0);
+    }
+    #[test_case]
+    fn breakpoints_are_not_rewound_without_swbreak() {
+        let mut stub = GdbStub::new();
+        let mut mem = FakeMemory([0x90; 16]);
+        let mut info: InterruptInfo = unsafe { core::mem::zeroed() };
+        *info.register_mut(Register::Rip) = 0x1000;
+        let replies = run_session(
+            &mut stub,
+            &mut mem,
+            &mut info,
+            StopReason::Debug,
+            &["qSupported:multiprocess+", "Z0,1008,1", "c"],
+        );
+        assert_eq!(replies[1], "PacketSize=1000");
+        assert_eq!(replies[2], "OK");
+        // GDB sees the address after the int3 and rewinds it by itself
+        *info.register_mut(Register::Rip) = 0x1009;
+        let replies = run_session(
+            &mut stub,
+            &mut mem,
+            &mut info,
+            StopReason::Breakpoint,
+            &["p10", "c"],
+        );
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "0910000000000000");
+        assert_eq!(*info.register_mut(Register::Rip), 0x1009);
+    }
+    #[test_case]
+    fn corrupted_packets_are_retransmitted() {
+        let mut buf = [0u8; 8];
+        let mut conn = FakeConnection {
+            input: format!("+$g#00{}", packet("g")).into_bytes(),
+            pos: 0,
+            output: Vec::new(),
+        };
+        assert_eq!(receive_packet(&mut conn, &mut buf), 1);
+        assert_eq!(conn.output, b"-+");
+        let mut conn = FakeConnection {
+            input: format!("{}{}", packet("123456789"), packet("m0,1")).into_bytes(),
+            pos: 0,
+            output: Vec::new(),
+        };
+        assert_eq!(receive_packet(&mut conn, &mut buf), 4);
+        assert_eq!(&buf[..4], b"m0,1");
+        assert_eq!(conn.output, b"-+");
+    }
+}
@@ -1,32 +1,45 @@
-use crate::mutex::Mutex;
+use crate::apic::route_gsi;
+use crate::interrupt::register_irq_handler;
+use crate::mmio::Mmio;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::x86::InterruptInfo;
 use core::mem::size_of;
-use core::ptr::read_volatile;
-use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 use core::time::Duration;
 
 const TIMER_CONFIG_LEVEL_TRIGGER: u64 = 1 << 1;
 const TIMER_CONFIG_INT_ENABLE: u64 = 1 << 2;
 const TIMER_CONFIG_USE_PERIODIC_MODE: u64 = 1 << 3;
+const TIMER_CAPABILITY_PERIODIC: u64 = 1 << 4;
+// Allows to set the accumulator of the periodic timer by writing the comparator
+const TIMER_CONFIG_SET_ACCUMULATOR: u64 = 1 << 6;
+const TIMER_CONFIG_INT_ROUTE_SHIFT: u64 = 9;
+const TIMER_CONFIG_INT_ROUTE_MASK: u64 = 0b11111 << TIMER_CONFIG_INT_ROUTE_SHIFT;
+
+/// Vector of the periodic timer tick (see init_timer_tick())
+pub const TIMER_TICK_VECTOR: u8 = 0x20;
+pub const TIMER_TICK_HZ: u64 = 100;
+// Comparator used for the timer tick
+const TIMER_TICK_COMPARATOR: usize = 0;
 
 #[repr(C)]
 struct TimerRegister {
-    configuration_and_capability: u64,
-    _reserved: [u64; 3],
+    configuration_and_capability: Mmio<u64>,
+    comparator_value: Mmio<u64>,
+    _fsb_interrupt_route: u64,
+    _reserved: u64,
 }
 const _: () = assert!(size_of::<TimerRegister>() == 0x20);
-impl TimerRegister {
-    unsafe fn write_config(&mut self, config: u64) {
-        write_volatile(&mut self.configuration_and_capability, config);
-    }
-}
 
 #[repr(C)]
 pub struct HpetRegisters {
-    capabilities_and_id: u64,
+    capabilities_and_id: Mmio<u64>,
     _reserved0: u64,
-    configuration: u64,
+    configuration: Mmio<u64>,
     _reserved1: [u64; 27],
-    main_counter_value: u64,
+    main_counter_value: Mmio<u64>,
     _reserved2: u64,
     timers: [TimerRegister; 32],
 }
@@ -34,61 +47,245 @@ const _: () = assert!(size_of::<HpetRegisters>() == 0x500);
 
 pub struct Hpet {
     registers: &'static mut HpetRegisters,
-    #[allow(unused)]
     num_of_timers: usize,
     freq: u64,
 }
 impl Hpet {
     pub fn new(registers: &'static mut HpetRegisters) -> Self {
-        let fs_per_count = registers.capabilities_and_id >> 32;
-        let num_of_timers = ((registers.capabilities_and_id >> 8) & 0b11111) as usize + 1;
+        let capabilities_and_id = registers.capabilities_and_id.read();
+        let fs_per_count = capabilities_and_id >> 32;
+        let num_of_timers = ((capabilities_and_id >> 8) & 0b11111) as usize + 1;
         let freq = 1_000_000_000_000_000 / fs_per_count;
         let mut hpet = Self {
             registers,
             num_of_timers,
             freq,
         };
-        unsafe {
-            hpet.globally_disable();
-            for i in 0..hpet.num_of_timers {
-                let timer = &mut hpet.registers.timers[i];
-                let mut config = read_volatile(&timer.configuration_and_capability);
-                config &= !(TIMER_CONFIG_INT_ENABLE
-                    | TIMER_CONFIG_USE_PERIODIC_MODE
-                    | TIMER_CONFIG_LEVEL_TRIGGER
-                    | (0b11111 << 9));
-                timer.write_config(config);
-            }
-            write_volatile(&mut hpet.registers.main_counter_value, 0);
-            hpet.globally_enable();
+        hpet.globally_disable();
+        for i in 0..hpet.num_of_timers {
+            hpet.registers.timers[i]
+                .configuration_and_capability
+                .update(|config| {
+                    config
+                        & !(TIMER_CONFIG_INT_ENABLE
+                            | TIMER_CONFIG_USE_PERIODIC_MODE
+                            | TIMER_CONFIG_LEVEL_TRIGGER
+                            | TIMER_CONFIG_INT_ROUTE_MASK)
+                });
         }
+        hpet.registers.main_counter_value.write(0);
+        hpet.globally_enable();
         hpet
     }
-    unsafe fn globally_disable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) & !0b11;
-        write_volatile(&mut self.registers.configuration, config);
+    fn globally_disable(&mut self) {
+        self.registers.configuration.update(|config| config & !0b11);
     }
-    unsafe fn globally_enable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) | 0b01;
-        write_volatile(&mut self.registers.configuration, config);
+    fn globally_enable(&mut self) {
+        self.registers.configuration.update(|config| config | 0b01);
     }
     pub fn main_counter(&self) -> u64 {
-        unsafe { read_volatile(&self.registers.main_counter_value) }
+        self.registers.main_counter_value.read()
     }
     pub fn freq(&self) -> u64 {
         self.freq
     }
+    pub fn num_of_timers(&self) -> usize {
+        self.num_of_timers
+    }
+    fn timer(&mut self, index: usize) -> Result<&mut TimerRegister> {
+        if index >= self.num_of_timers {
+            return Err("HPET comparator index out of range");
+        }
+        Ok(&mut self.registers.timers[index])
+    }
+    /// Returns a GSI that the comparator can be routed to.
+    /// GSIs beyond the legacy ISA IRQs are preferred to avoid sharing them.
+    pub fn routable_gsi(&mut self, index: usize) -> Result<u32> {
+        let capability = (self.timer(index)?.configuration_and_capability.read() >> 32) as u32;
+        (16..32)
+            .chain(0..16)
+            .find(|gsi| capability & (1 << gsi) != 0)
+            .ok_or("HPET comparator can not be routed to any GSI")
+    }
+    fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * self.freq as u128 / 1_000_000_000) as u64
+    }
+    /// Starts the comparator to raise an edge-triggered interrupt on the I/O APIC input gsi
+    /// after duration, or every duration if mode is TimerMode::Periodic.
+    pub fn start_timer(
+        &mut self,
+        index: usize,
+        mode: TimerMode,
+        duration: Duration,
+        gsi: u32,
+    ) -> Result<()> {
+        let ticks = self.duration_to_ticks(duration).max(1);
+        let now = self.main_counter();
+        let timer = self.timer(index)?;
+        let capability = timer.configuration_and_capability.read();
+        if gsi >= 32 || capability & (1 << (32 + gsi)) == 0 {
+            return Err("HPET comparator can not be routed to the GSI");
+        }
+        let config = capability
+            & !(TIMER_CONFIG_USE_PERIODIC_MODE
+                | TIMER_CONFIG_LEVEL_TRIGGER
+                | TIMER_CONFIG_INT_ROUTE_MASK)
+            | TIMER_CONFIG_INT_ENABLE
+            | (gsi as u64) << TIMER_CONFIG_INT_ROUTE_SHIFT;
+        match mode {
+            TimerMode::OneShot => {
+                timer.configuration_and_capability.write(config);
+                timer.comparator_value.write(now + ticks);
+            }
+            TimerMode::Periodic => {
+                if capability & TIMER_CAPABILITY_PERIODIC == 0 {
+                    return Err("HPET comparator does not support the periodic mode");
+                }
+                timer
+                    .configuration_and_capability
+                    .write(config | TIMER_CONFIG_USE_PERIODIC_MODE | TIMER_CONFIG_SET_ACCUMULATOR);
+                // The first write sets the time of the first interrupt,
+                // and the second one sets the period.
+                timer.comparator_value.write(now + ticks);
+                timer.comparator_value.write(ticks);
+            }
+        }
+        Ok(())
+    }
+    pub fn stop_timer(&mut self, index: usize) -> Result<()> {
+        self.timer(index)?
+            .configuration_and_capability
+            .update(|config| config & !(TIMER_CONFIG_INT_ENABLE | TIMER_CONFIG_USE_PERIODIC_MODE));
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
 }
-static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+static HPET: InterruptSafeMutex<Option<Hpet>> = InterruptSafeMutex::new(None);
+// Address of the main counter of the global HPET (0 before it is set) and its frequency,
+// so that global_timestamp() can be called from interrupt handlers without the lock.
+static MAIN_COUNTER_ADDR: AtomicU64 = AtomicU64::new(0);
+static MAIN_COUNTER_FREQ: AtomicU64 = AtomicU64::new(0);
 pub fn set_global_hpet(hpet: Hpet) {
-    assert!(HPET.lock().is_none());
-    *HPET.lock() = Some(hpet);
+    let mut global = HPET.lock();
+    assert!(global.is_none());
+    let main_counter = &hpet.registers.main_counter_value as *const Mmio<u64> as u64;
+    MAIN_COUNTER_FREQ.store(hpet.freq(), Ordering::SeqCst);
+    MAIN_COUNTER_ADDR.store(main_counter, Ordering::SeqCst);
+    *global = Some(hpet);
+}
+pub fn is_hpet_initialized() -> bool {
+    MAIN_COUNTER_ADDR.load(Ordering::SeqCst) != 0
 }
 pub fn global_timestamp() -> Duration {
-    if let Some(hpet) = &*HPET.lock() {
-        let ns = hpet.main_counter() as u128 * 1_000_000_000 / hpet.freq() as u128;
-        Duration::from_nanos(ns as u64)
-    } else {
-        Duration::ZERO
+    let main_counter = MAIN_COUNTER_ADDR.load(Ordering::SeqCst) as *const Mmio<u64>;
+    if main_counter.is_null() {
+        return Duration::ZERO;
+    }
+    // SAFETY: The registers of the global HPET are mapped forever.
+    let count = unsafe { &*main_counter }.read();
+    let ns = count as u128 * 1_000_000_000 / MAIN_COUNTER_FREQ.load(Ordering::SeqCst) as u128;
+    Duration::from_nanos(ns as u64)
+}
+
+/// Starts the comparator of the global HPET and routes its interrupt to the vector.
+pub fn start_hpet_timer(
+    index: usize,
+    mode: TimerMode,
+    duration: Duration,
+    vector: u8,
+) -> Result<()> {
+    let mut hpet = HPET.lock();
+    let hpet = hpet.as_mut().ok_or("HPET is not initialized")?;
+    let gsi = hpet.routable_gsi(index)?;
+    route_gsi(gsi, vector)?;
+    hpet.start_timer(index, mode, duration, gsi)
+}
+pub fn stop_hpet_timer(index: usize) -> Result<()> {
+    HPET.lock()
+        .as_mut()
+        .ok_or("HPET is not initialized")?
+        .stop_timer(index)
+}
+
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Starts the periodic timer tick of TIMER_TICK_HZ on TIMER_TICK_VECTOR.
+pub fn init_timer_tick() -> Result<()> {
+    register_irq_handler(TIMER_TICK_VECTOR, on_timer_tick)?;
+    start_hpet_timer(
+        TIMER_TICK_COMPARATOR,
+        TimerMode::Periodic,
+        Duration::from_nanos(1_000_000_000 / TIMER_TICK_HZ),
+        TIMER_TICK_VECTOR,
+    )
+}
+fn on_timer_tick(_: u8, _: &mut InterruptInfo) {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+/// Number of the timer ticks since init_timer_tick()
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn comparators_are_programmed_for_each_mode() {
+        let mut backing = [0u64; size_of::<HpetRegisters>() / 8];
+        // 10 MHz, 3 comparators
+        backing[0] = 100_000_000 << 32 | 2 << 8;
+        let timer_config = |i: usize| 0x100 / 8 + i * 4;
+        // Comparator 0: periodic capable, routable to GSI 2 and 20
+        backing[timer_config(0)] = (1 << 2 | 1 << 20) << 32 | TIMER_CAPABILITY_PERIODIC;
+        // Comparator 1: one-shot only, routable to GSI 8
+        backing[timer_config(1)] = (1 << 8) << 32;
+        let registers = unsafe { &mut *(backing.as_mut_ptr() as *mut HpetRegisters) };
+        let mut hpet = Hpet::new(registers);
+        assert_eq!(hpet.freq(), 10_000_000);
+        assert_eq!(hpet.num_of_timers(), 3);
+        assert_eq!(hpet.routable_gsi(0), Ok(20));
+        assert_eq!(hpet.routable_gsi(1), Ok(8));
+        assert!(hpet.routable_gsi(2).is_err());
+        assert!(hpet.routable_gsi(3).is_err());
+
+        hpet.start_timer(0, TimerMode::Periodic, Duration::from_millis(10), 20)
+            .unwrap();
+        let config = hpet.registers.timers[0].configuration_and_capability.read();
+        assert_ne!(config & TIMER_CONFIG_INT_ENABLE, 0);
+        assert_ne!(config & TIMER_CONFIG_USE_PERIODIC_MODE, 0);
+        assert_eq!(
+            (config & TIMER_CONFIG_INT_ROUTE_MASK) >> TIMER_CONFIG_INT_ROUTE_SHIFT,
+            20
+        );
+        // The period is written last
+        assert_eq!(hpet.registers.timers[0].comparator_value.read(), 100_000);
+
+        assert!(hpet
+            .start_timer(1, TimerMode::Periodic, Duration::from_millis(1), 8)
+            .is_err());
+        assert!(hpet
+            .start_timer(1, TimerMode::OneShot, Duration::from_millis(1), 2)
+            .is_err());
+        hpet.start_timer(1, TimerMode::OneShot, Duration::from_millis(1), 8)
+            .unwrap();
+        let config = hpet.registers.timers[1].configuration_and_capability.read();
+        assert_eq!(config & TIMER_CONFIG_USE_PERIODIC_MODE, 0);
+        assert_eq!(
+            hpet.registers.timers[1].comparator_value.read(),
+            hpet.main_counter() + 10_000
+        );
+
+        hpet.stop_timer(0).unwrap();
+        let config = hpet.registers.timers[0].configuration_and_capability.read();
+        assert_eq!(
+            config & (TIMER_CONFIG_INT_ENABLE | TIMER_CONFIG_USE_PERIODIC_MODE),
+            0
+        );
     }
 }
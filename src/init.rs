@@ -1,13 +1,29 @@
 extern crate alloc;
 
 use crate::acpi::AcpiRsdpStruct;
+use crate::address_space::create_direct_map;
+use crate::address_space::set_kernel_page_table;
+use crate::address_space::virt_to_phys;
+use crate::address_space::IDENTITY_MAP;
 use crate::allocator::ALLOCATOR;
+use crate::apic::disable_legacy_pic;
+use crate::apic::init_io_apics;
+use crate::apic::init_local_apic;
+use crate::apic::start_local_apic_timer;
+use crate::frame_allocator::num_of_free_frames;
+use crate::frame_allocator::num_of_used_frames;
+use crate::frame_allocator::set_global_frame_allocator;
+use crate::frame_allocator::BitmapFrameAllocator;
 use crate::graphics::draw_test_pattern;
 use crate::graphics::fill_rect;
 use crate::graphics::Bitmap;
+use crate::hpet::init_timer_tick;
 use crate::hpet::set_global_hpet;
 use crate::hpet::Hpet;
 use crate::info;
+use crate::mmio::map_mmio_registers;
+use crate::pe::PeImage;
+use crate::result::Result;
 use crate::uefi::exit_from_efi_boot_services;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiMemoryType;
@@ -15,12 +31,14 @@ use crate::uefi::EfiMemoryType::*;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
 use crate::uefi::VramBufferInfo;
+use crate::x86::enable_page_attributes;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
 use crate::x86::PAGE_SIZE;
 use crate::x86::PML4;
 use alloc::boxed::Box;
 use core::cmp::max;
+use core::time::Duration;
 
 pub fn init_basic_runtime(
     image_handle: EfiHandle,
@@ -28,12 +46,14 @@ pub fn init_basic_runtime(
 ) -> MemoryMapHolder {
     let mut memory_map = MemoryMapHolder::new();
     exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
-    ALLOCATOR.init_with_mmap(&memory_map);
+    let frame_allocator =
+        BitmapFrameAllocator::new(&memory_map).expect("Failed to create the frame allocator");
+    set_global_frame_allocator(frame_allocator);
     memory_map
 }
 
-pub fn init_paging(memory_map: &MemoryMapHolder) {
-    let mut table = PML4::new();
+/// image_base: image_base of the LoadedImageProtocol for the running kernel
+pub fn init_paging(memory_map: &MemoryMapHolder, image_base: u64) {
     let mut end_of_mem = 0x1_0000_0000u64;
     for e in memory_map.iter() {
         match e.memory_type() {
@@ -46,16 +66,52 @@ pub fn init_paging(memory_map: &MemoryMapHolder) {
             _ => (),
         }
     }
+    let mut table = create_identity_mapped_page_table(end_of_mem);
+    create_direct_map(&mut table, end_of_mem).expect("Failed to create the direct map");
+    let image = unsafe { PeImage::new(image_base) }.expect("Failed to parse the kernel image");
+    map_kernel_image(&mut table, &image).expect("Failed to map the kernel image");
+    enable_page_attributes().expect("Failed to enable page attributes");
+    let table = Box::leak(table);
+    let table_phys =
+        virt_to_phys(table as *const PML4 as u64).expect("Failed to get the page table address");
+    unsafe {
+        write_cr3(table_phys as *const PML4);
+    }
+    set_kernel_page_table(table);
+}
+
+/// Remaps the loaded kernel image with the permissions of each section
+/// so that the code can not be modified and the data can not be executed.
+fn map_kernel_image(table: &mut PML4, image: &PeImage) -> Result<()> {
+    let base = image.image_base();
+    table.create_mapping(
+        base,
+        base + image.headers_size(),
+        base,
+        PageAttr::ReadOnlyKernel,
+    )?;
+    for section in image.sections() {
+        info!("{section:?}");
+        let start = base + section.virtual_address();
+        let end = start + section.virtual_size();
+        table.create_mapping(start, end, start, section.page_attr()?)?;
+    }
+    Ok(())
+}
+
+/// Maps [0, end_of_mem) to the same physical addresses, except for page 0.
+/// This is needed until the kernel stops using the physical addresses given by
+/// the firmware (see address_space.rs). The pages are not executable, and the
+/// kernel image is remapped with the permissions of its sections.
+fn create_identity_mapped_page_table(end_of_mem: u64) -> Box<PML4> {
+    assert!(end_of_mem <= IDENTITY_MAP.end);
+    let mut table = PML4::new();
     table
-        .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteKernel)
+        .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteNoExecuteLocalKernel)
         .expect("Failed to create initial page mapping");
     // Unmap page 0 to detect null ptr dereference
+    table.unmap(0, 4096).expect("Failed to unmap page 0");
     table
-        .create_mapping(0, 4096, 0, PageAttr::NotPresent)
-        .expect("Failed to unmap page 0");
-    unsafe {
-        write_cr3(Box::into_raw(table));
-    }
 }
 
 pub fn init_hpet(acpi: &AcpiRsdpStruct) {
@@ -63,11 +119,28 @@ pub fn init_hpet(acpi: &AcpiRsdpStruct) {
     let hpet = hpet
         .base_address()
         .expect("Failed to get HPET base address");
-    info!("HPET is at {hpet:#p}");
+    info!("HPET is at {hpet:#018X}");
+    let hpet = unsafe { map_mmio_registers(hpet) }.expect("Failed to map HPET registers");
     let hpet = Hpet::new(hpet);
     set_global_hpet(hpet);
 }
 
+pub fn init_interrupt_controllers(acpi: &AcpiRsdpStruct) {
+    let madt = acpi.madt().expect("Failed to get MADT from ACPI");
+    info!("{} CPUs found in MADT", madt.num_of_cpus());
+    for e in madt.entries() {
+        info!("MADT: {e:X?}");
+    }
+    if madt.has_legacy_pics() {
+        disable_legacy_pic();
+    }
+    init_local_apic().expect("Failed to initialize the Local APIC");
+    init_io_apics(madt).expect("Failed to initialize the I/O APICs");
+    init_timer_tick().expect("Failed to start the timer tick");
+    start_local_apic_timer(Duration::from_millis(10))
+        .expect("Failed to start the Local APIC timer");
+}
+
 pub fn init_allocator(memory_map: &MemoryMapHolder) {
     let mut total_memory_pages = 0;
     for e in memory_map.iter() {
@@ -79,6 +152,12 @@ pub fn init_allocator(memory_map: &MemoryMapHolder) {
     }
     let total_memory_size_mib = total_memory_pages * 4096 / 1024 / 1024;
     info!("Total: {total_memory_pages} pages = {total_memory_size_mib} MiB");
+    info!(
+        "Frames: {} free, {} used",
+        num_of_free_frames(),
+        num_of_used_frames()
+    );
+    info!("{:?}", ALLOCATOR.stats());
 }
 
 pub fn init_display(vram: &mut VramBufferInfo) {
@@ -87,3 +166,141 @@ pub fn init_display(vram: &mut VramBufferInfo) {
     fill_rect(vram, 0x000000, 0, 0, vw, vh).expect("fill_rect failed");
     draw_test_pattern(vram);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::TranslationResult;
+
+    #[test_case]
+    fn initial_page_table_maps_memory_identically() {
+        use crate::frame_allocator::num_of_free_frames;
+        use crate::x86::is_1g_page_supported;
+        let free = num_of_free_frames();
+        let end_of_mem = 0x1_0000_0000;
+        let mut table = create_identity_mapped_page_table(end_of_mem);
+        // The first 2MiB page is split to unmap the page 0.
+        for virt in [0x1000, 0x1234, 0x1F_FFFF] {
+            assert_eq!(
+                table.translate(virt),
+                Ok(TranslationResult::PageMapped4K { phys: virt })
+            );
+        }
+        // The rest of the first 1GiB page is mapped with 2MiB pages.
+        for virt in [0x20_0000, 0x1234_5678, 0x3FFF_FFFF] {
+            assert_eq!(
+                table.translate(virt),
+                Ok(TranslationResult::PageMapped2M { phys: virt })
+            );
+        }
+        for virt in [0x4000_0000, 0xDEAD_BEEF, end_of_mem - 1] {
+            let expected = if is_1g_page_supported() {
+                TranslationResult::PageMapped1G { phys: virt }
+            } else {
+                TranslationResult::PageMapped2M { phys: virt }
+            };
+            assert_eq!(table.translate(virt), Ok(expected));
+        }
+        assert_eq!(table.translate(0), Err("PT entry is not present"));
+        assert_eq!(table.translate(0xFFF), Err("PT entry is not present"));
+        assert_eq!(
+            table.translate(end_of_mem),
+            Err("PDPT entry is not present")
+        );
+        assert_eq!(
+            table.translate(0x0000_8000_0000_0000),
+            Err("PML4 entry is not present")
+        );
+        table.unmap(0, end_of_mem).unwrap();
+        assert_eq!(num_of_free_frames(), free);
+    }
+
+    #[test_case]
+    fn remapping_part_of_huge_page_splits_it() {
+        use crate::frame_allocator::num_of_free_frames;
+        let mut table = PML4::new();
+        let free = num_of_free_frames();
+        let virt = 0x8000_0000;
+        let phys = 0x1_0000_0000;
+        table
+            .create_mapping(virt, virt + 0x4000_0000, phys, PageAttr::ReadWriteKernel)
+            .unwrap();
+        table
+            .create_mapping(
+                virt + 0x20_3000,
+                virt + 0x20_4000,
+                0x5000,
+                PageAttr::ReadWriteIo,
+            )
+            .unwrap();
+        assert_eq!(
+            table.translate(virt + 0x20_3123),
+            Ok(TranslationResult::PageMapped4K { phys: 0x5123 })
+        );
+        assert_eq!(
+            table.translate(virt + 0x20_4000),
+            Ok(TranslationResult::PageMapped4K {
+                phys: phys + 0x20_4000
+            })
+        );
+        assert_eq!(
+            table.translate(virt + 0x20_2FFF),
+            Ok(TranslationResult::PageMapped4K {
+                phys: phys + 0x20_2FFF
+            })
+        );
+        assert_eq!(
+            table.translate(virt + 0x40_0000),
+            Ok(TranslationResult::PageMapped2M {
+                phys: phys + 0x40_0000
+            })
+        );
+        table.unmap(virt, virt + 0x4000_0000).unwrap();
+        assert_eq!(num_of_free_frames(), free);
+    }
+
+    #[test_case]
+    fn unmap_frees_empty_tables() {
+        use crate::frame_allocator::num_of_free_frames;
+        let mut table = PML4::new();
+        let free = num_of_free_frames();
+        let virt = 0x7F00_0000_0000;
+        // Not aligned to 2MiB, so a PDPT, a PD and PTs are allocated.
+        table
+            .create_mapping(virt, virt + 0x40_1000, 0x1000, PageAttr::ReadWriteKernel)
+            .unwrap();
+        assert!(num_of_free_frames() < free);
+        // Partial unmap keeps the tables that still have mappings.
+        table.unmap(virt + 0x1000, virt + 0x3000).unwrap();
+        assert_eq!(
+            table.translate(virt + 0x1000),
+            Err("PT entry is not present")
+        );
+        assert_eq!(
+            table.translate(virt),
+            Ok(TranslationResult::PageMapped4K { phys: 0x1000 })
+        );
+        assert_eq!(
+            table.translate(virt + 0x3000),
+            Ok(TranslationResult::PageMapped4K { phys: 0x4000 })
+        );
+        table.unmap(virt, virt + 0x40_1000).unwrap();
+        assert_eq!(table.translate(virt), Err("PML4 entry is not present"));
+        assert_eq!(num_of_free_frames(), free);
+        // Unmapping a part of a huge page splits it.
+        table
+            .create_mapping(virt, virt + 0x20_0000, 0, PageAttr::ReadWriteKernel)
+            .unwrap();
+        table.unmap(virt + 0x10_0000, virt + 0x20_0000).unwrap();
+        assert_eq!(
+            table.translate(virt + 0xF_F000),
+            Ok(TranslationResult::PageMapped4K { phys: 0xF_F000 })
+        );
+        assert_eq!(
+            table.translate(virt + 0x10_0000),
+            Err("PT entry is not present")
+        );
+        table.unmap(virt, virt + 0x20_0000).unwrap();
+        assert_eq!(num_of_free_frames(), free);
+    }
+}
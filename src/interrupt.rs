@@ -0,0 +1,112 @@
+//! Registry of interrupt handlers
+//!
+//! Every vector in the IDT enters inthandler() in x86.rs, which calls the
+//! handler registered here for the vector. Drivers either use a fixed
+//! vector (e.g. TIMER_TICK_VECTOR) with register_irq_handler(), or get a
+//! free one with allocate_irq_vector() and route their IRQ to it.
+//! EOI is sent by inthandler() after the handler returns.
+
+use crate::result::Result;
+use crate::x86::InterruptInfo;
+use core::mem::transmute;
+use core::ops::Range;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+/// Called with interrupts disabled, on an interrupt stack.
+pub type InterruptHandler = fn(vector: u8, info: &mut InterruptInfo);
+
+/// Vectors given by allocate_irq_vector().
+/// 0x20-0x2F are reserved for the fixed vectors, and 0xE0- for the legacy PICs and spurious interrupts.
+const ALLOCATABLE_VECTORS: Range<usize> = 0x30..0xE0;
+/// Fixed vector reserved for the unit tests that raise an interrupt with "int 0x2F"
+pub const TEST_VECTOR: u8 = 0x2F;
+const _: () = assert!((TEST_VECTOR as usize) < ALLOCATABLE_VECTORS.start);
+
+/// Handlers are stored as function pointers in atomics (0 for none), so that
+/// inthandler() can look them up without taking a lock. Registration updates
+/// the slots with compare_exchange() so it does not need a lock either.
+struct HandlerTable {
+    handlers: [AtomicUsize; 256],
+}
+impl HandlerTable {
+    const fn new() -> Self {
+        // Only used to initialize the array, so it is not shared
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NONE: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            handlers: [NONE; 256],
+        }
+    }
+    fn get(&self, vector: u8) -> Option<InterruptHandler> {
+        let handler = self.handlers[vector as usize].load(Ordering::SeqCst);
+        // SAFETY: Non-zero values are stored only by register() from InterruptHandler
+        (handler != 0).then(|| unsafe { transmute::<usize, InterruptHandler>(handler) })
+    }
+    fn register(&self, vector: u8, handler: InterruptHandler) -> Result<()> {
+        self.handlers[vector as usize]
+            .compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst)
+            .map(|_| ())
+            .or(Err(
+                "Interrupt handler is already registered for the vector",
+            ))
+    }
+    fn unregister(&self, vector: u8) -> Result<()> {
+        match self.handlers[vector as usize].swap(0, Ordering::SeqCst) {
+            0 => Err("Interrupt handler is not registered for the vector"),
+            _ => Ok(()),
+        }
+    }
+    fn allocate(&self, handler: InterruptHandler) -> Result<u8> {
+        ALLOCATABLE_VECTORS
+            .clone()
+            .map(|v| v as u8)
+            .find(|&v| self.register(v, handler).is_ok())
+            .ok_or("No free interrupt vector")
+    }
+}
+
+static HANDLERS: HandlerTable = HandlerTable::new();
+
+/// Registers the handler for the vector. Exceptions (0-31) can also be hooked (e.g. by a debugger).
+pub fn register_irq_handler(vector: u8, handler: InterruptHandler) -> Result<()> {
+    HANDLERS.register(vector, handler)
+}
+pub fn unregister_irq_handler(vector: u8) -> Result<()> {
+    HANDLERS.unregister(vector)
+}
+/// Finds a free vector and registers the handler for it.
+/// The vector should be released with unregister_irq_handler().
+pub fn allocate_irq_vector(handler: InterruptHandler) -> Result<u8> {
+    HANDLERS.allocate(handler)
+}
+/// Called for every interrupt, so this does not take any lock.
+pub fn irq_handler(vector: u8) -> Option<InterruptHandler> {
+    HANDLERS.get(vector)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn handler_a(_: u8, _: &mut InterruptInfo) {}
+    fn handler_b(_: u8, _: &mut InterruptInfo) {}
+
+    #[test_case]
+    fn vectors_are_allocated_and_released() {
+        let table = HandlerTable::new();
+        table.register(0x20, handler_a).unwrap();
+        assert!(table.register(0x20, handler_b).is_err());
+        assert_eq!(table.allocate(handler_a), Ok(0x30));
+        assert_eq!(table.allocate(handler_b), Ok(0x31));
+        assert!(table.get(0x31).is_some());
+        table.unregister(0x30).unwrap();
+        assert!(table.unregister(0x30).is_err());
+        assert_eq!(table.allocate(handler_b), Ok(0x30));
+        for _ in ALLOCATABLE_VECTORS.start + 2..ALLOCATABLE_VECTORS.end {
+            table.allocate(handler_a).unwrap();
+        }
+        assert!(table.allocate(handler_a).is_err());
+        assert!(table.get(0xE0).is_none());
+    }
+}
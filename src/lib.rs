@@ -8,17 +8,30 @@
 #![reexport_test_harness_main = "run_unit_tests"]
 #![no_main]
 pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod exception;
 pub mod executor;
+pub mod frame_allocator;
+pub mod gdb;
 pub mod graphics;
 pub mod hpet;
 pub mod init;
+pub mod interrupt;
+pub mod mmio;
 pub mod mutex;
+pub mod pe;
 pub mod print;
 pub mod qemu;
 pub mod result;
 pub mod serial;
+pub mod stack;
+pub mod symbols;
 pub mod uefi;
+pub mod vma;
+pub mod watchpoint;
 pub mod x86;
 
 #[cfg(test)]
@@ -27,6 +40,11 @@ pub mod test_runner;
 #[cfg(test)]
 #[no_mangle]
 fn efi_main(image_handle: uefi::EfiHandle, efi_system_table: &uefi::EfiSystemTable) {
+    if let Ok(image) = uefi::locate_loaded_image_protocol(image_handle, efi_system_table) {
+        backtrace::set_kernel_image(image.image_base, image.image_size);
+    }
     init::init_basic_runtime(image_handle, efi_system_table);
+    // Some tests raise interrupts (e.g. watchpoint.rs)
+    let (_gdt, _idt) = x86::init_exceptions();
     run_unit_tests()
 }
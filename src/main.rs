@@ -4,16 +4,20 @@
 
 use core::panic::PanicInfo;
 use core::time::Duration;
+use wasabi::backtrace::set_kernel_image;
+use wasabi::backtrace::Backtrace;
 use wasabi::error;
 use wasabi::executor::Executor;
 use wasabi::executor::Task;
 use wasabi::executor::TimeoutFuture;
+use wasabi::gdb::init_gdb_stub;
 use wasabi::hpet::global_timestamp;
 use wasabi::info;
 use wasabi::init::init_allocator;
 use wasabi::init::init_basic_runtime;
 use wasabi::init::init_display;
 use wasabi::init::init_hpet;
+use wasabi::init::init_interrupt_controllers;
 use wasabi::init::init_paging;
 use wasabi::print::hexdump;
 use wasabi::print::set_global_vram;
@@ -21,12 +25,18 @@ use wasabi::println;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
 use wasabi::serial::SerialPort;
+use wasabi::stack::alloc_kernel_stack;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiSystemTable;
 use wasabi::warn;
+use wasabi::x86::call_on_stack;
+use wasabi::x86::enable_interrupts;
 use wasabi::x86::init_exceptions;
+use wasabi::x86::trigger_debug_interrupt;
+
+const KERNEL_STACK_SIZE: usize = 1024 * 1024;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
@@ -37,6 +47,8 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
         .expect("Failed to get LoadedImageProtocol");
     println!("image_base: {:#018X}", loaded_image_protocol.image_base);
     println!("image_size: {:#018X}", loaded_image_protocol.image_size);
+    let image_base = loaded_image_protocol.image_base;
+    set_kernel_image(image_base, loaded_image_protocol.image_size);
     info!("info");
     warn!("warn");
     error!("error");
@@ -51,10 +63,29 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     info!("Hello, Non-UEFI world!");
     init_allocator(&memory_map);
 
+    // Paging is initialized first so that the interrupt stacks have guard pages
+    init_paging(&memory_map, image_base);
     let (_gdt, _idt) = init_exceptions();
-    init_paging(&memory_map);
 
     init_hpet(acpi);
+    init_interrupt_controllers(acpi);
+    // The stack given by the firmware has no guard page.
+    // This one has since init_paging() is done.
+    let stack = alloc_kernel_stack(KERNEL_STACK_SIZE, "kernel")
+        .expect("Failed to allocate the kernel stack");
+    unsafe { call_on_stack(stack, run_kernel) };
+    unreachable!("run_kernel() should not return");
+}
+
+extern "sysv64" fn run_kernel() {
+    match init_gdb_stub() {
+        Ok(()) => {
+            info!("Waiting for GDB on COM2");
+            trigger_debug_interrupt();
+        }
+        Err(e) => info!("GDB stub is not started: {e}"),
+    }
+    enable_interrupts();
     let t0 = global_timestamp();
     let task1 = Task::new(async move {
         for i in 100..=103 {
@@ -95,5 +126,6 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("PANIC: {info:?}");
+    Backtrace::for_panic().print();
     exit_qemu(QemuExitCode::Fail);
 }
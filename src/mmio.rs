@@ -0,0 +1,154 @@
+//! Access to memory-mapped device registers
+//!
+//! Register blocks are mapped to the MMIO window with PageAttr::ReadWriteIo
+//! (uncached) by map_mmio(), and each register is wrapped with Mmio<T>
+//! so that it is always accessed with volatile reads and writes.
+
+use crate::address_space::is_kernel_page_table_active;
+use crate::address_space::phys_to_virt;
+use crate::address_space::with_kernel_page_table;
+use crate::address_space::MMIO_WINDOW;
+use crate::result::Result;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use core::mem::size_of;
+use core::ops::Range;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+/// A device register of type T
+#[repr(transparent)]
+pub struct Mmio<T: Copy> {
+    value: T,
+}
+impl<T: Copy> Mmio<T> {
+    pub fn read(&self) -> T {
+        unsafe { read_volatile(&self.value) }
+    }
+    pub fn write(&mut self, value: T) {
+        unsafe { write_volatile(&mut self.value, value) }
+    }
+    pub fn update(&mut self, f: impl FnOnce(T) -> T) {
+        let value = f(self.read());
+        self.write(value);
+    }
+}
+
+/// Virtual address range to map device registers
+struct MmioWindow {
+    // Next address in the window to be mapped
+    next_addr: AtomicU64,
+    end: u64,
+}
+impl MmioWindow {
+    const fn new(range: Range<u64>) -> Self {
+        Self {
+            next_addr: AtomicU64::new(range.start),
+            end: range.end,
+        }
+    }
+    fn map(&self, table: &mut PML4, phys: u64, len: usize) -> Result<u64> {
+        let page_mask = PAGE_SIZE as u64 - 1;
+        let phys_start = phys & !page_mask;
+        let phys_end = (phys + len as u64 + page_mask) & !page_mask;
+        let size = phys_end - phys_start;
+        // Advance only if the range fits in the window
+        let virt = self
+            .next_addr
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                (next + size <= self.end).then_some(next + size)
+            })
+            .or(Err("MMIO window is exhausted"))?;
+        if let Err(e) = table.create_mapping(virt, virt + size, phys_start, PageAttr::ReadWriteIo) {
+            // Drop the part that was mapped, and give the range back if it is still the last one
+            table.unmap(virt, virt + size)?;
+            let _ = self.next_addr.compare_exchange(
+                virt + size,
+                virt,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            return Err(e);
+        }
+        Ok(virt + (phys - phys_start))
+    }
+}
+static MMIO: MmioWindow = MmioWindow::new(MMIO_WINDOW);
+
+/// Maps [phys, phys + len) to the MMIO window and returns the virtual address of phys.
+/// Before the kernel page table is loaded, the address in the current mapping is returned.
+pub fn map_mmio(phys: u64, len: usize) -> Result<u64> {
+    if len == 0 {
+        return Err("len should not be 0");
+    }
+    if !is_kernel_page_table_active() {
+        return Ok(phys_to_virt(phys));
+    }
+    with_kernel_page_table(|table| MMIO.map(table, phys, len))
+}
+
+/// Maps the register block R at phys.
+///
+/// # Safety
+/// phys should point the registers that have the layout of R,
+/// and the returned reference should be the only one that accesses them.
+pub unsafe fn map_mmio_registers<R>(phys: u64) -> Result<&'static mut R> {
+    let virt = map_mmio(phys, size_of::<R>())?;
+    Ok(&mut *(virt as *mut R))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_allocator::alloc_frame_4k;
+    use crate::frame_allocator::free_frame_4k;
+    use crate::x86::TranslationResult;
+
+    #[test_case]
+    fn mmio_accessors_read_and_write() {
+        #[repr(C)]
+        struct Registers {
+            a: Mmio<u32>,
+            b: Mmio<u64>,
+        }
+        let mut backing = [0u64; 2];
+        let regs = unsafe {
+            map_mmio_registers::<Registers>(&mut backing as *mut [u64; 2] as u64).unwrap()
+        };
+        regs.a.write(0x1234_5678);
+        regs.b.write(0xDEAD_BEEF);
+        regs.b.update(|v| v | 1);
+        assert_eq!(regs.a.read(), 0x1234_5678);
+        assert_eq!(regs.b.read(), 0xDEAD_BEEF);
+        assert_eq!(backing, [0x1234_5678, 0xDEAD_BEEF]);
+    }
+
+    #[test_case]
+    fn registers_are_mapped_to_the_window() {
+        let base = 0x7000_0000_0000;
+        let window = MmioWindow::new(base..base + 3 * PAGE_SIZE as u64);
+        let mut table = PML4::new();
+        let frame = alloc_frame_4k().unwrap();
+        let virt = window.map(&mut table, frame + 0x10, 8).unwrap();
+        assert_eq!(virt, base + 0x10);
+        assert!(matches!(
+            table.translate(virt),
+            Ok(TranslationResult::PageMapped4K { phys }) if phys == frame + 0x10
+        ));
+        // Registers across a page boundary take two pages
+        let virt = window.map(&mut table, frame + 0xFFC, 8).unwrap();
+        assert_eq!(virt, base + 0x1FFC);
+        assert!(matches!(
+            table.translate(virt + 4),
+            Ok(TranslationResult::PageMapped4K { phys }) if phys == frame + 0x1000
+        ));
+        // A failed call does not consume the window
+        assert!(window.map(&mut table, frame, 8).is_err());
+        assert_eq!(window.next_addr.load(Ordering::SeqCst), base + 0x3000);
+        table.unmap(base, base + 0x3000).unwrap();
+        free_frame_4k(frame).unwrap();
+    }
+}
@@ -12,8 +12,12 @@
 //! to it will be safe.
 
 use crate::result::Result;
+use crate::x86::are_interrupts_enabled;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
 use core::cell::SyncUnsafeCell;
 use core::fmt::Debug;
+use core::mem::ManuallyDrop;
 use core::ops::Deref;
 use core::ops::DerefMut;
 use core::panic::Location;
@@ -127,3 +131,123 @@ impl<T: Default> Default for Mutex<T> {
         Self::new(T::default())
     }
 }
+
+pub struct InterruptSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    were_interrupts_enabled: bool,
+}
+impl<'a, T> Deref for InterruptSafeMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+impl<'a, T> DerefMut for InterruptSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+impl<'a, T> Drop for InterruptSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the lock before enabling interrupts again
+        // so that interrupt handlers never see the lock taken.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.were_interrupts_enabled {
+            enable_interrupts();
+        }
+    }
+}
+impl<'a, T> Debug for InterruptSafeMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "InterruptSafeMutexGuard {{ guard: {:?} }}", *self.guard)
+    }
+}
+
+/// Mutex that also disables interrupts while it is held
+///
+/// Since the holder can not be interrupted on the same CPU,
+/// interrupt handlers can take this lock without deadlocks.
+/// If the lock is taken again while it is held on the same CPU
+/// (e.g. by an exception raised in the critical section),
+/// lock() panics with the location of both the mutex and the caller.
+pub struct InterruptSafeMutex<T> {
+    mutex: Mutex<T>,
+}
+impl<T: Sized> Debug for InterruptSafeMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "InterruptSafe{:?}", self.mutex)
+    }
+}
+impl<T: Sized> InterruptSafeMutex<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            mutex: Mutex::new(data),
+        }
+    }
+    #[track_caller]
+    pub fn lock(&self) -> InterruptSafeMutexGuard<T> {
+        let were_interrupts_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        InterruptSafeMutexGuard {
+            guard: ManuallyDrop::new(self.mutex.lock()),
+            were_interrupts_enabled,
+        }
+    }
+    /// Returns Err instead of spinning if the lock is taken.
+    /// Exception handlers should use this since the exception
+    /// may be raised while the lock is held on the same CPU.
+    #[track_caller]
+    pub fn try_lock(&self) -> Result<InterruptSafeMutexGuard<T>> {
+        let were_interrupts_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        match self.mutex.try_lock() {
+            Ok(guard) => Ok(InterruptSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                were_interrupts_enabled,
+            }),
+            Err(e) => {
+                if were_interrupts_enabled {
+                    enable_interrupts();
+                }
+                Err(e)
+            }
+        }
+    }
+}
+impl<T: Default> Default for InterruptSafeMutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn interrupts_are_disabled_while_interrupt_safe_mutex_is_held() {
+        let mutex = InterruptSafeMutex::new(0);
+        let were_interrupts_enabled = are_interrupts_enabled();
+        {
+            let mut locked = mutex.lock();
+            *locked += 1;
+            assert!(!are_interrupts_enabled());
+        }
+        assert_eq!(are_interrupts_enabled(), were_interrupts_enabled);
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn try_lock_fails_while_interrupt_safe_mutex_is_held() {
+        let mutex = InterruptSafeMutex::new(0);
+        let were_interrupts_enabled = are_interrupts_enabled();
+        {
+            let _locked = mutex.lock();
+            assert!(mutex.try_lock().is_err());
+        }
+        assert_eq!(are_interrupts_enabled(), were_interrupts_enabled);
+        assert!(mutex.try_lock().is_ok());
+    }
+}
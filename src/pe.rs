@@ -0,0 +1,207 @@
+//! Minimal PE/COFF parser for the loaded kernel image
+//!
+//! The image is loaded by the UEFI firmware at image_base with all the
+//! sections placed at image_base + VirtualAddress, so the headers are
+//! read directly from the memory instead of the file.
+
+use crate::result::Result;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+const IMAGE_DOS_SIGNATURE: [u8; 2] = *b"MZ";
+const IMAGE_NT_SIGNATURE: [u8; 4] = *b"PE\0\0";
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+// Offset of e_lfanew in IMAGE_DOS_HEADER
+const OFFSET_OF_NT_HEADER_OFFSET: usize = 0x3C;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+struct NtHeader {
+    // PE Format: Signature + COFF File Header
+    signature: [u8; 4],
+    machine: u16,
+    number_of_sections: u16,
+    _time_date_stamp: u32,
+    _pointer_to_symbol_table: u32,
+    _number_of_symbols: u32,
+    size_of_optional_header: u16,
+    _characteristics: u16,
+}
+const _: () = assert!(size_of::<NtHeader>() == 24);
+
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct SectionHeader {
+    name: [u8; 8],
+    virtual_size: u32,
+    virtual_address: u32,
+    _size_of_raw_data: u32,
+    _pointer_to_raw_data: u32,
+    _pointer_to_relocations: u32,
+    _pointer_to_line_numbers: u32,
+    _number_of_relocations: u16,
+    _number_of_line_numbers: u16,
+    characteristics: u32,
+}
+const _: () = assert!(size_of::<SectionHeader>() == 40);
+
+impl SectionHeader {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(8);
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+    /// Offset from image_base
+    pub fn virtual_address(&self) -> u64 {
+        self.virtual_address as u64
+    }
+    /// Size in memory, rounded up to PAGE_SIZE
+    pub fn virtual_size(&self) -> u64 {
+        (self.virtual_size as u64 + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_READ != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE != 0
+    }
+    /// Returns the attribute to map this section with, following W^X.
+    pub fn page_attr(&self) -> Result<PageAttr> {
+        match (self.is_writable(), self.is_executable()) {
+            (true, true) => Err("Section is both writable and executable"),
+            (true, false) => Ok(PageAttr::ReadWriteNoExecuteKernel),
+            (false, true) => Ok(PageAttr::ReadExecuteKernel),
+            (false, false) => Ok(PageAttr::ReadOnlyKernel),
+        }
+    }
+}
+impl core::fmt::Debug for SectionHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Section {:8} {:#010X} - {:#010X} {}{}{}",
+            self.name(),
+            self.virtual_address(),
+            self.virtual_address() + self.virtual_size(),
+            if self.is_readable() { "R" } else { "-" },
+            if self.is_writable() { "W" } else { "-" },
+            if self.is_executable() { "X" } else { "-" },
+        )
+    }
+}
+
+pub struct PeImage {
+    image_base: u64,
+    sections: &'static [SectionHeader],
+}
+impl PeImage {
+    /// # Safety
+    /// image_base should point to a PE image loaded in memory that alives forever.
+    pub unsafe fn new(image_base: u64) -> Result<Self> {
+        let base = image_base as *const u8;
+        if slice::from_raw_parts(base, 2) != IMAGE_DOS_SIGNATURE {
+            return Err("DOS signature not found");
+        }
+        let nt_header_offset =
+            (base.add(OFFSET_OF_NT_HEADER_OFFSET) as *const u32).read_unaligned();
+        let nt_header_ptr = base.add(nt_header_offset as usize) as *const NtHeader;
+        let nt_header = nt_header_ptr.read_unaligned();
+        if nt_header.signature != IMAGE_NT_SIGNATURE {
+            return Err("PE signature not found");
+        }
+        if nt_header.machine != IMAGE_FILE_MACHINE_AMD64 {
+            return Err("Image is not for x86_64");
+        }
+        let sections = (nt_header_ptr.add(1) as *const u8)
+            .add(nt_header.size_of_optional_header as usize)
+            as *const SectionHeader;
+        let sections = slice::from_raw_parts(sections, nt_header.number_of_sections as usize);
+        Ok(Self {
+            image_base,
+            sections,
+        })
+    }
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+    /// Size of the headers in memory, which is the range before the first section.
+    pub fn headers_size(&self) -> u64 {
+        self.sections
+            .iter()
+            .map(|s| s.virtual_address())
+            .min()
+            .unwrap_or(PAGE_SIZE as u64)
+    }
+    pub fn sections(&self) -> impl Iterator<Item = &SectionHeader> {
+        self.sections.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(align(4096))]
+    struct FakeImage([u8; 4096]);
+
+    fn write_section(
+        image: &mut FakeImage,
+        offset: usize,
+        name: &[u8],
+        va: u32,
+        characteristics: u32,
+    ) {
+        image.0[offset..offset + name.len()].copy_from_slice(name);
+        image.0[offset + 8..offset + 12].copy_from_slice(&0x1234u32.to_le_bytes());
+        image.0[offset + 12..offset + 16].copy_from_slice(&va.to_le_bytes());
+        image.0[offset + 36..offset + 40].copy_from_slice(&characteristics.to_le_bytes());
+    }
+
+    #[test_case]
+    fn sections_are_parsed_with_wx_attributes() {
+        const NT_HEADER_OFFSET: usize = 0x80;
+        const SIZE_OF_OPTIONAL_HEADER: u16 = 0xF0;
+        let mut image = FakeImage([0; 4096]);
+        image.0[0..2].copy_from_slice(b"MZ");
+        image.0[0x3C..0x40].copy_from_slice(&(NT_HEADER_OFFSET as u32).to_le_bytes());
+        let nt = &mut image.0[NT_HEADER_OFFSET..];
+        nt[0..4].copy_from_slice(b"PE\0\0");
+        nt[4..6].copy_from_slice(&IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+        nt[6..8].copy_from_slice(&3u16.to_le_bytes());
+        nt[20..22].copy_from_slice(&SIZE_OF_OPTIONAL_HEADER.to_le_bytes());
+        let sections = NT_HEADER_OFFSET + size_of::<NtHeader>() + SIZE_OF_OPTIONAL_HEADER as usize;
+        let code = 0x6000_0020;
+        let rodata = 0x4000_0040;
+        let data = 0xC000_0040;
+        write_section(&mut image, sections, b".text", 0x1000, code);
+        write_section(&mut image, sections + 40, b".rdata", 0x3000, rodata);
+        write_section(&mut image, sections + 80, b".data", 0x5000, data);
+
+        let pe = unsafe { PeImage::new(&image as *const FakeImage as u64) }.unwrap();
+        assert_eq!(pe.headers_size(), 0x1000);
+        assert_eq!(pe.sections().count(), 3);
+        let mut sections = pe.sections();
+        let text = sections.next().unwrap();
+        assert_eq!(text.name(), ".text");
+        assert_eq!(text.page_attr(), Ok(PageAttr::ReadExecuteKernel));
+        assert_eq!(text.virtual_size(), 0x2000);
+        let rdata = sections.next().unwrap();
+        assert_eq!(rdata.name(), ".rdata");
+        assert_eq!(rdata.page_attr(), Ok(PageAttr::ReadOnlyKernel));
+        let data = sections.next().unwrap();
+        assert_eq!(data.name(), ".data");
+        assert_eq!(data.virtual_address(), 0x5000);
+        assert_eq!(data.page_attr(), Ok(PageAttr::ReadWriteNoExecuteKernel));
+
+        image.0[NT_HEADER_OFFSET] = b'X';
+        assert!(unsafe { PeImage::new(&image as *const FakeImage as u64) }.is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::graphics::BitmapTextWriter;
 use crate::mutex::Mutex;
 use crate::serial::SerialPort;
+use crate::symbols::lookup_symbol;
 use crate::uefi::VramBufferInfo;
 use core::fmt;
 use core::mem::size_of;
@@ -82,7 +83,9 @@ fn hexdump_bytes(bytes: &[u8]) {
                     }
                 );
             }
-            println!("|");
+            print!("|");
+            print_symbols_in_line(&ascii);
+            println!();
             offset += 16;
             i = 0;
         }
@@ -104,7 +107,19 @@ fn hexdump_bytes(bytes: &[u8]) {
                 }
             );
         }
-        println!("|");
+        print!("|");
+        print_symbols_in_line(&ascii[0..old_i]);
+        println!();
+    }
+}
+/// Prints the functions pointed by the 8-byte values in a line of hexdump,
+/// e.g. return addresses in a dump of a stack.
+fn print_symbols_in_line(line: &[u8]) {
+    for (i, qword) in line.chunks_exact(8).enumerate() {
+        let value = u64::from_le_bytes(qword.try_into().unwrap());
+        if let Some(symbol) = lookup_symbol(value) {
+            print!(" +{:X}: {symbol}", i * 8);
+        }
     }
 }
 pub fn hexdump<T: Sized>(data: &T) {
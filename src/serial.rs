@@ -17,6 +17,10 @@ impl SerialPort {
         // Use COM1 as I/O port 0x3f8
         Self::new(0x3f8)
     }
+    pub fn new_for_com2() -> Self {
+        // Use COM2 as I/O port 0x2f8
+        Self::new(0x2f8)
+    }
     pub fn init(&mut self) {
         // Disable all interrupts
         write_io_port_u8(self.base + 1, 0x00);
@@ -33,6 +37,10 @@ impl SerialPort {
         // IRQs enabled, RTS/DSR set
         write_io_port_u8(self.base + 4, 0x0B);
     }
+    /// Raises the IRQ (IRQ4 for COM1, IRQ3 for COM2) when data is received.
+    pub fn enable_receive_interrupt(&self) {
+        write_io_port_u8(self.base + 1, 0x01);
+    }
     pub fn loopback_test(&self) -> Result<()> {
         // Set in loopback mode
         write_io_port_u8(self.base + 4, 0x1e);
@@ -61,10 +69,7 @@ impl SerialPort {
         if read_io_port_u8(self.base + 5) & 0x01 == 0 {
             None
         } else {
-            let c = read_io_port_u8(self.base);
-            // Enable FIFO, clear them, with 14-byte threshold
-            write_io_port_u8(self.base + 2, 0xC7);
-            Some(c)
+            Some(read_io_port_u8(self.base))
         }
     }
 }
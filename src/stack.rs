@@ -0,0 +1,176 @@
+//! Kernel stacks with guard pages
+//!
+//! Each stack is mapped in STACK_REGION with an unmapped guard page just
+//! below it, so that an overflow causes a page fault instead of silently
+//! corrupting the memory below. Pushing the exception frame of that fault
+//! to the same stack fails again, which ends up in a double fault that is
+//! handled on its own IST stack. The handler can then ask
+//! find_overflowed_stack() which stack has run out.
+//!
+//! The stack given by the firmware has no guard page, so efi_main() moves
+//! onto a stack from alloc_kernel_stack() with call_on_stack() once paging
+//! is initialized.
+
+use crate::address_space::is_kernel_page_table_active;
+use crate::address_space::with_kernel_page_table;
+use crate::address_space::STACK_REGION;
+use crate::frame_allocator::alloc_frames;
+use crate::frame_allocator::free_frames;
+use crate::frame_allocator::FRAME_SIZE_4K;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use core::ops::Range;
+
+const MAX_STACKS: usize = 32;
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+
+#[derive(Clone, Debug)]
+struct KernelStack {
+    // Mapped range of the stack. The guard page is just below range.start.
+    range: Range<u64>,
+    name: &'static str,
+}
+impl KernelStack {
+    fn guard(&self) -> Range<u64> {
+        self.range.start - GUARD_SIZE..self.range.start
+    }
+}
+
+struct StackList {
+    entries: [Option<KernelStack>; MAX_STACKS],
+    // Next address in the region to be used (as a guard page)
+    next_addr: u64,
+    region: Range<u64>,
+}
+impl StackList {
+    const fn new(region: Range<u64>) -> Self {
+        const NONE: Option<KernelStack> = None;
+        Self {
+            entries: [NONE; MAX_STACKS],
+            next_addr: region.start,
+            region,
+        }
+    }
+    /// Maps a stack of size bytes with frames and returns the range of it.
+    fn alloc(&mut self, table: &mut PML4, size: usize, name: &'static str) -> Result<Range<u64>> {
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err("size should be a non-zero multiple of PAGE_SIZE");
+        }
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_none())
+            .ok_or("Too many kernel stacks")?;
+        let start = self.next_addr + GUARD_SIZE;
+        let end = start + size as u64;
+        if end > self.region.end {
+            return Err("Stack region is exhausted");
+        }
+        let num_of_frames = size / FRAME_SIZE_4K;
+        let phys = alloc_frames(num_of_frames, FRAME_SIZE_4K)?;
+        if let Err(e) = table.create_mapping(start, end, phys, PageAttr::ReadWriteNoExecuteKernel) {
+            // Drop the part that was mapped before the failure
+            table.unmap(start, end)?;
+            free_frames(phys, num_of_frames)?;
+            return Err(e);
+        }
+        self.next_addr = end;
+        *slot = Some(KernelStack {
+            range: start..end,
+            name,
+        });
+        Ok(start..end)
+    }
+    fn find_by_guard(&self, addr: u64) -> Option<&KernelStack> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|e| e.guard().contains(&addr))
+    }
+}
+
+static STACKS: InterruptSafeMutex<StackList> =
+    InterruptSafeMutex::new(StackList::new(STACK_REGION));
+
+/// Allocates a kernel stack of size bytes and returns the address of its top.
+/// Before the kernel page table is loaded, the stack is allocated without a guard
+/// page (as in the unit tests), and an overflow of it is not detected.
+pub fn alloc_kernel_stack(size: usize, name: &'static str) -> Result<u64> {
+    if !is_kernel_page_table_active() {
+        let phys = alloc_frames(size.div_ceil(FRAME_SIZE_4K), FRAME_SIZE_4K)?;
+        return Ok(phys + size as u64);
+    }
+    let mut stacks = STACKS.lock();
+    let range = with_kernel_page_table(|table| stacks.alloc(table, size, name))?;
+    Ok(range.end)
+}
+
+/// Returns the name of the stack whose guard page contains any of addrs.
+/// Called from the double fault handler with CR2 and the saved RSP.
+/// Returns "unknown" if the list is locked, since the fault can be raised while it is held.
+pub fn find_overflowed_stack(addrs: &[u64]) -> Option<&'static str> {
+    let Ok(stacks) = STACKS.try_lock() else {
+        return Some("unknown");
+    };
+    addrs
+        .iter()
+        .find_map(|&addr| stacks.find_by_guard(addr))
+        .map(|s| s.name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::call_on_stack;
+    use crate::x86::TranslationResult;
+    use core::hint::black_box;
+    use core::sync::atomic::AtomicU64;
+    use core::sync::atomic::Ordering;
+
+    #[test_case]
+    fn stacks_are_separated_by_guard_pages() {
+        let base = 0x7000_0000_0000;
+        let mut stacks = StackList::new(base..base + 0x100_0000);
+        let mut table = PML4::new();
+        let a = stacks.alloc(&mut table, 4 * PAGE_SIZE, "a").unwrap();
+        let b = stacks.alloc(&mut table, 2 * PAGE_SIZE, "b").unwrap();
+        assert_eq!(a, base + 0x1000..base + 0x5000);
+        assert_eq!(b, base + 0x6000..base + 0x8000);
+        for page in a.clone().chain(b.clone()).step_by(PAGE_SIZE) {
+            assert!(matches!(
+                table.translate(page),
+                Ok(TranslationResult::PageMapped4K { .. })
+            ));
+        }
+        assert!(table.translate(base).is_err());
+        assert!(table.translate(base + 0x5000).is_err());
+        assert_eq!(stacks.find_by_guard(base + 0xFF8).unwrap().name, "a");
+        assert_eq!(stacks.find_by_guard(base + 0x5000).unwrap().name, "b");
+        assert!(stacks.find_by_guard(base + 0x1000).is_none());
+        assert!(stacks.alloc(&mut table, 0x123, "c").is_err());
+        table.unmap(base, base + 0x8000).unwrap();
+    }
+
+    static ADDR_OF_LOCAL: AtomicU64 = AtomicU64::new(0);
+    extern "sysv64" fn record_addr_of_local() {
+        let local = black_box(0u64);
+        ADDR_OF_LOCAL.store(&local as *const u64 as u64, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn functions_are_called_on_the_given_stack() {
+        const SIZE: usize = 4 * PAGE_SIZE;
+        // Paging is not initialized in the tests, so this has no guard page.
+        let top = alloc_kernel_stack(SIZE, "test").unwrap();
+        unsafe { call_on_stack(top, record_addr_of_local) };
+        let addr = ADDR_OF_LOCAL.load(Ordering::SeqCst);
+        assert!((top - SIZE as u64..top).contains(&addr), "{addr:#X}");
+        // The original stack is used again after the call
+        record_addr_of_local();
+        assert!(!(top - SIZE as u64..top).contains(&ADDR_OF_LOCAL.load(Ordering::SeqCst)));
+        free_frames(top - SIZE as u64, SIZE / FRAME_SIZE_4K).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! Symbol table embedded in the kernel image
+//!
+//! The linker writes a map file of the image (-Clink-arg=/map in
+//! .cargo/config.toml) and scripts/embed_symbols.py copies the function
+//! names in it into the .ksyms section of the image before it is launched.
+//! The layout of the section is (integers are in little endian):
+//!
+//! ```text
+//!   0x00: b"WSYMTAB\0"
+//!   0x08: u32 number of entries
+//!   0x0C: u32 offset of the string area from the start of the section
+//!   0x10: entries sorted by rva: { u32 rva, u32 name_offset, u32 name_len }
+//!   ....: string area (names are not terminated)
+//! ```
+//!
+//! An entry with an empty name marks the end of the previous function.
+//! The size of the section is given by WASABI_SYMBOL_TABLE_SIZE in the [env]
+//! of .cargo/config.toml, and the script fails if the table does not fit.
+//! The section stays empty if the script is not run, and addresses are
+//! printed without names in that case.
+
+use crate::backtrace::offset_in_kernel_image;
+use crate::result::Result;
+use core::cell::UnsafeCell;
+use core::fmt;
+
+const SYMBOL_TABLE_MAGIC: [u8; 8] = *b"WSYMTAB\0";
+/// Should be large enough to hold the names of all functions in the image.
+const SYMBOL_TABLE_SIZE: usize = parse_size(env!("WASABI_SYMBOL_TABLE_SIZE"));
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 12;
+
+/// Parses a decimal number at compile time.
+const fn parse_size(s: &str) -> usize {
+    let s = s.as_bytes();
+    assert!(!s.is_empty(), "WASABI_SYMBOL_TABLE_SIZE is empty");
+    let mut size = 0;
+    let mut i = 0;
+    while i < s.len() {
+        assert!(
+            s[i].is_ascii_digit(),
+            "WASABI_SYMBOL_TABLE_SIZE should be a decimal number"
+        );
+        size = size * 10 + (s[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(size >= HEADER_SIZE, "WASABI_SYMBOL_TABLE_SIZE is too small");
+    size
+}
+
+/// The contents are rewritten outside of the program, so they are behind
+/// UnsafeCell to prevent the compiler from assuming the initial value.
+#[repr(C, align(8))]
+struct SymbolTableSection(UnsafeCell<[u8; SYMBOL_TABLE_SIZE]>);
+impl SymbolTableSection {
+    const fn empty() -> Self {
+        let mut data = [0; SYMBOL_TABLE_SIZE];
+        let mut i = 0;
+        while i < SYMBOL_TABLE_MAGIC.len() {
+            data[i] = SYMBOL_TABLE_MAGIC[i];
+            i += 1;
+        }
+        Self(UnsafeCell::new(data))
+    }
+}
+// SAFETY: The section is only written before the image is loaded.
+unsafe impl Sync for SymbolTableSection {}
+
+/// Filled by scripts/embed_symbols.py after the image is linked.
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOL_TABLE_SECTION: SymbolTableSection = SymbolTableSection::empty();
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+impl<'a> SymbolTable<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.get(..SYMBOL_TABLE_MAGIC.len()) != Some(&SYMBOL_TABLE_MAGIC) {
+            return Err("Invalid symbol table magic");
+        }
+        let num_of_entries = read_u32(data, 8).ok_or("Symbol table is too small")? as usize;
+        let strings_offset = read_u32(data, 12).ok_or("Symbol table is too small")? as usize;
+        let entries = data
+            .get(HEADER_SIZE..HEADER_SIZE + num_of_entries * ENTRY_SIZE)
+            .ok_or("Symbol table entries are out of range")?;
+        let strings = data
+            .get(strings_offset..)
+            .ok_or("Symbol table strings are out of range")?;
+        Ok(Self { entries, strings })
+    }
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+    fn rva(&self, index: usize) -> u32 {
+        read_u32(self.entries, index * ENTRY_SIZE).unwrap()
+    }
+    fn name(&self, index: usize) -> Option<&'a str> {
+        let offset = read_u32(self.entries, index * ENTRY_SIZE + 4)? as usize;
+        let len = read_u32(self.entries, index * ENTRY_SIZE + 8)? as usize;
+        let name = self.strings.get(offset..offset + len)?;
+        core::str::from_utf8(name).ok()
+    }
+    /// Returns the function which contains rva and the offset of rva in it.
+    fn lookup(&self, rva: u64) -> Option<(&'a str, u64)> {
+        // Find the last entry whose rva is not greater than the given one.
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.rva(mid) as u64 <= rva {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let index = lo.checked_sub(1)?;
+        let name = self.name(index).filter(|name| !name.is_empty())?;
+        Some((name, rva - self.rva(index) as u64))
+    }
+}
+
+fn kernel_symbol_table() -> Option<SymbolTable<'static>> {
+    // SAFETY: The section is never written while the kernel is running.
+    let data = unsafe { &*SYMBOL_TABLE_SECTION.0.get() };
+    SymbolTable::parse(data).ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#X}", self.name, self.offset)
+    }
+}
+
+/// Returns the function which contains addr if addr is in the kernel image.
+pub fn lookup_symbol(addr: u64) -> Option<Symbol> {
+    let rva = offset_in_kernel_image(addr)?;
+    let (name, offset) = kernel_symbol_table()?.lookup(rva)?;
+    Some(Symbol { name, offset })
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn symbols_are_looked_up_by_rva() {
+        let symbols: [(u32, &str); 3] = [(0x1000, "foo"), (0x1010, "bar::baz"), (0x1080, "")];
+        let strings_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+        let mut data = Vec::new();
+        data.extend_from_slice(&SYMBOL_TABLE_MAGIC);
+        data.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(strings_offset as u32).to_le_bytes());
+        let mut name_offset = 0;
+        for (rva, name) in symbols {
+            data.extend_from_slice(&rva.to_le_bytes());
+            data.extend_from_slice(&(name_offset as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            name_offset += name.len();
+        }
+        for (_, name) in symbols {
+            data.extend_from_slice(name.as_bytes());
+        }
+        let table = SymbolTable::parse(&data).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.lookup(0xFFF), None);
+        assert_eq!(table.lookup(0x1000), Some(("foo", 0)));
+        assert_eq!(table.lookup(0x100F), Some(("foo", 0xF)));
+        assert_eq!(table.lookup(0x1010), Some(("bar::baz", 0)));
+        assert_eq!(table.lookup(0x107F), Some(("bar::baz", 0x6F)));
+        // Addresses after the end marker are not in any function
+        assert_eq!(table.lookup(0x1080), None);
+        assert_eq!(table.lookup(0x2000), None);
+        // An empty section has no symbols
+        let mut empty = [0u8; HEADER_SIZE];
+        empty[..8].copy_from_slice(&SYMBOL_TABLE_MAGIC);
+        assert_eq!(SymbolTable::parse(&empty).unwrap().lookup(0x1000), None);
+        assert!(SymbolTable::parse(&data[1..]).is_err());
+    }
+}
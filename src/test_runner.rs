@@ -1,3 +1,4 @@
+use crate::backtrace::Backtrace;
 use crate::qemu::exit_qemu;
 use crate::qemu::QemuExitCode;
 use crate::serial::SerialPort;
@@ -32,5 +33,6 @@ pub fn test_runner(tests: &[&dyn Testable]) -> ! {
 fn panic(info: &PanicInfo) -> ! {
     let mut sw = SerialPort::new_for_com1();
     writeln!(sw, "PANIC during test: {info:?}").unwrap();
+    Backtrace::for_panic().print();
     exit_qemu(QemuExitCode::Fail);
 }
@@ -116,6 +116,7 @@ impl Default for MemoryMapHolder {
     }
 }
 
+#[derive(Clone)]
 pub struct MemoryMapIterator<'a> {
     map: &'a MemoryMapHolder,
     ofs: usize,
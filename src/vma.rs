@@ -0,0 +1,201 @@
+//! Virtual memory areas (VMAs) that are backed by frames on demand
+//!
+//! reserve_region() only takes a range of virtual addresses in VMA_REGION.
+//! Each page is backed by a zero-filled frame when it is touched for the
+//! first time, in the page fault handler (see handle_page_fault()).
+//!
+//! The list of VMAs is a fixed-size array so that the page fault handler
+//! does not depend on the heap. The handler does not wait for the locks of
+//! the VMAs, the kernel page table and the frame allocator, so a fault on a
+//! page in VMAs while holding one of them is reported as unresolved.
+
+use crate::address_space::phys_to_virt;
+use crate::address_space::try_with_kernel_page_table;
+use crate::address_space::with_kernel_page_table;
+use crate::address_space::VMA_REGION;
+use crate::frame_allocator::free_frame_4k;
+use crate::frame_allocator::try_alloc_frame_4k;
+use crate::mutex::InterruptSafeMutex;
+use crate::result::Result;
+use crate::x86::PageAttr;
+use crate::x86::TranslationResult;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use core::ops::Range;
+use core::ptr::write_bytes;
+
+const MAX_VMAS: usize = 64;
+// Error code of #PF: the fault was caused by a page-level protection violation
+const PF_ERROR_CODE_PRESENT: u64 = 1 << 0;
+
+#[derive(Clone, Debug)]
+pub struct Vma {
+    range: Range<u64>,
+    attr: PageAttr,
+    name: &'static str,
+}
+impl Vma {
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+struct VmaList {
+    entries: [Option<Vma>; MAX_VMAS],
+    // Next address in the region to be reserved
+    next_addr: u64,
+    region: Range<u64>,
+}
+impl VmaList {
+    const fn new(region: Range<u64>) -> Self {
+        const NONE: Option<Vma> = None;
+        Self {
+            entries: [NONE; MAX_VMAS],
+            next_addr: region.start,
+            region,
+        }
+    }
+    fn reserve(&mut self, size: usize, attr: PageAttr, name: &'static str) -> Result<Range<u64>> {
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err("size should be a non-zero multiple of PAGE_SIZE");
+        }
+        if matches!(attr, PageAttr::NotPresent) {
+            return Err("VMA should be mapped with a present attribute");
+        }
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_none())
+            .ok_or("Too many VMAs")?;
+        let start = self.next_addr;
+        let end = start + size as u64;
+        if end > self.region.end {
+            return Err("VMA region is exhausted");
+        }
+        self.next_addr = end;
+        *slot = Some(Vma {
+            range: start..end,
+            attr,
+            name,
+        });
+        Ok(start..end)
+    }
+    fn find(&self, addr: u64) -> Option<&Vma> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|e| e.range.contains(&addr))
+    }
+    /// Backs the page that contains addr with a zero-filled frame.
+    fn populate(&self, table: &mut PML4, addr: u64) -> Result<()> {
+        let vma = self.find(addr).ok_or("Address is not in any VMA")?;
+        let page = addr & !(PAGE_SIZE as u64 - 1);
+        // If the frame allocator is not held by the faulting code, the page tables
+        // can also be allocated below since nothing else runs on this CPU meanwhile.
+        let frame = try_alloc_frame_4k()?;
+        unsafe { write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE) };
+        table
+            .create_mapping(page, page + PAGE_SIZE as u64, frame, vma.attr)
+            .or_else(|e| {
+                free_frame_4k(frame)?;
+                Err(e)
+            })
+    }
+    /// Frees the frames that back the VMA which starts at start, and removes the VMA.
+    /// The virtual address range is not reused.
+    fn release(&mut self, table: &mut PML4, start: u64) -> Result<()> {
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|e| e.as_ref().is_some_and(|e| e.range.start == start))
+            .ok_or("VMA not found")?;
+        let range = slot.take().ok_or("VMA not found")?.range;
+        for page in range.clone().step_by(PAGE_SIZE) {
+            if let Ok(TranslationResult::PageMapped4K { phys }) = table.translate(page) {
+                free_frame_4k(phys)?;
+            }
+        }
+        table.unmap(range.start, range.end)
+    }
+}
+
+static VMAS: InterruptSafeMutex<VmaList> = InterruptSafeMutex::new(VmaList::new(VMA_REGION));
+
+/// Reserves a virtual address range of size bytes that will be mapped with attr on demand.
+pub fn reserve_region(size: usize, attr: PageAttr, name: &'static str) -> Result<Range<u64>> {
+    VMAS.lock().reserve(size, attr, name)
+}
+/// Releases the VMA that starts at start and frees its frames.
+pub fn release_region(start: u64) -> Result<()> {
+    let mut vmas = VMAS.lock();
+    with_kernel_page_table(|table| vmas.release(table, start))
+}
+/// Returns None if the VMAs are being updated, since this is also
+/// called from the page fault handler to report the fault.
+pub fn find_vma(addr: u64) -> Option<Vma> {
+    VMAS.try_lock().ok()?.find(addr).cloned()
+}
+
+/// Called from the page fault handler.
+/// Returns Ok if the fault is resolved and the faulting instruction can be retried.
+/// Faults outside of VMAs return Err without taking any lock.
+pub fn handle_page_fault(addr: u64, error_code: u64) -> Result<()> {
+    if error_code & PF_ERROR_CODE_PRESENT != 0 {
+        return Err("Page is present (protection violation)");
+    }
+    if !VMA_REGION.contains(&addr) {
+        return Err("Address is not in any VMA");
+    }
+    // The fault may be raised while the VMAs are being updated
+    let vmas = VMAS.try_lock()?;
+    if vmas.find(addr).is_none() {
+        return Err("Address is not in any VMA");
+    }
+    try_with_kernel_page_table(|table| vmas.populate(table, addr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_allocator::num_of_free_frames;
+
+    #[test_case]
+    fn pages_are_populated_on_first_touch() {
+        let base = 0x7000_0000_0000;
+        let mut vmas = VmaList::new(base..base + 0x100_0000);
+        let mut table = PML4::new();
+        let range = vmas
+            .reserve(16 * PAGE_SIZE, PageAttr::ReadWriteNoExecuteKernel, "test")
+            .unwrap();
+        assert_eq!(range, base..base + 16 * PAGE_SIZE as u64);
+        assert!(table.translate(base).is_err());
+        let free = num_of_free_frames();
+        vmas.populate(&mut table, base + 0x1234).unwrap();
+        let Ok(TranslationResult::PageMapped4K { phys }) = table.translate(base + 0x1000) else {
+            panic!("Page is not mapped");
+        };
+        let page =
+            unsafe { core::slice::from_raw_parts(phys_to_virt(phys) as *const u8, PAGE_SIZE) };
+        assert!(page.iter().all(|&b| b == 0));
+        // Only the touched page is backed
+        assert!(table.translate(base).is_err());
+        assert!(table.translate(base + 0x2000).is_err());
+        assert!(vmas
+            .populate(&mut table, base + 16 * PAGE_SIZE as u64)
+            .is_err());
+        vmas.release(&mut table, base).unwrap();
+        assert!(vmas.find(base).is_none());
+        assert_eq!(num_of_free_frames(), free);
+    }
+
+    #[test_case]
+    fn faults_are_not_resolved_while_the_vmas_are_held() {
+        let _vmas = VMAS.lock();
+        assert!(handle_page_fault(VMA_REGION.start, 0).is_err());
+        // Addresses outside of VMA_REGION are rejected without any lock
+        assert!(handle_page_fault(VMA_REGION.end, 0).is_err());
+    }
+}
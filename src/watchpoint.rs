@@ -0,0 +1,282 @@
+//! Hardware watchpoints with the debug registers (DR0-DR3, DR6 and DR7)
+//!
+//! Up to four addresses can be watched on the current CPU. The access raises
+//! #DB, which is reported with a backtrace by inthandler() (or by the GDB
+//! stub if it is started) and the execution continues. For example, to find
+//! who clobbers a header of an allocation:
+//!
+//! ```text
+//! set_watchpoint(Watchpoint::write(header_addr, 8))?;
+//! ```
+//!
+//! See SDM Vol.3 18.2 "Debug Registers".
+
+use crate::backtrace::Backtrace;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::read_debug_address_register;
+use crate::x86::read_dr6;
+use crate::x86::read_dr7;
+use crate::x86::write_debug_address_register;
+use crate::x86::write_dr6;
+use crate::x86::write_dr7;
+use crate::x86::InterruptInfo;
+use crate::x86::Register;
+use crate::x86::RFLAGS_RF;
+use core::fmt;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+pub const NUM_OF_WATCHPOINTS: usize = 4;
+/// DR6 bits which tell the breakpoint conditions that were met (B0-B3)
+const DR6_HIT_MASK: u64 = 0b1111;
+/// Value of DR6 without any status bits set
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+static NUM_OF_HITS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    /// Raises #DB before the instruction at the address is executed.
+    Execute,
+    /// Raises #DB after the instruction which writes to the range.
+    Write,
+    /// Raises #DB after the instruction which reads or writes the range.
+    ReadWrite,
+}
+impl WatchpointKind {
+    /// R/W field in DR7
+    fn rw_bits(self) -> u64 {
+        match self {
+            WatchpointKind::Execute => 0b00,
+            WatchpointKind::Write => 0b01,
+            WatchpointKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub kind: WatchpointKind,
+    /// 1, 2, 4 or 8 bytes. Always 1 for Execute.
+    pub len: usize,
+}
+impl Watchpoint {
+    pub fn execute(addr: u64) -> Self {
+        Self {
+            addr,
+            kind: WatchpointKind::Execute,
+            len: 1,
+        }
+    }
+    pub fn write(addr: u64, len: usize) -> Self {
+        Self {
+            addr,
+            kind: WatchpointKind::Write,
+            len,
+        }
+    }
+    pub fn read_write(addr: u64, len: usize) -> Self {
+        Self {
+            addr,
+            kind: WatchpointKind::ReadWrite,
+            len,
+        }
+    }
+    /// Returns the DR7 bits to enable this watchpoint in DRn.
+    fn dr7_bits(&self, index: usize) -> Result<u64> {
+        let len_bits = match (self.kind, self.len) {
+            (WatchpointKind::Execute, 1) => 0b00,
+            (WatchpointKind::Execute, _) => {
+                return Err("Length of an execution breakpoint should be 1")
+            }
+            (_, 1) => 0b00,
+            (_, 2) => 0b01,
+            (_, 4) => 0b11,
+            (_, 8) => 0b10,
+            _ => return Err("Length of a watchpoint should be 1, 2, 4 or 8"),
+        };
+        if self.addr % self.len as u64 != 0 {
+            return Err("Address of a watchpoint should be aligned to its length");
+        }
+        // L0-L3 in bit 0, 2, 4 and 6, R/W0 and LEN0 in bit 16-19, R/W1 and LEN1 in bit 20-23...
+        let control = (len_bits << 2 | self.kind.rw_bits()) << (16 + index * 4);
+        Ok(local_enable_bit(index) | control)
+    }
+    /// Decodes the watchpoint in DRn from DR7. Returns None if it is disabled.
+    fn from_dr7(dr7: u64, index: usize, addr: u64) -> Option<Self> {
+        if dr7 & local_enable_bit(index) == 0 {
+            return None;
+        }
+        let control = (dr7 >> (16 + index * 4)) & 0b1111;
+        let kind = match control & 0b11 {
+            0b00 => WatchpointKind::Execute,
+            0b01 => WatchpointKind::Write,
+            0b11 => WatchpointKind::ReadWrite,
+            // I/O breakpoints are not used
+            _ => return None,
+        };
+        let len = match control >> 2 {
+            0b00 => 1,
+            0b01 => 2,
+            0b11 => 4,
+            _ => 8,
+        };
+        Some(Self { addr, kind, len })
+    }
+}
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            WatchpointKind::Execute => write!(f, "execution of {:#018X}", self.addr),
+            WatchpointKind::Write => {
+                write!(f, "write to {:#018X} ({} bytes)", self.addr, self.len)
+            }
+            WatchpointKind::ReadWrite => {
+                write!(f, "read/write of {:#018X} ({} bytes)", self.addr, self.len)
+            }
+        }
+    }
+}
+
+fn local_enable_bit(index: usize) -> u64 {
+    1 << (index * 2)
+}
+/// Bits of all the fields of DRn in DR7
+fn dr7_mask(index: usize) -> u64 {
+    0b11 << (index * 2) | 0b1111 << (16 + index * 4)
+}
+fn find_free_slot(dr7: u64) -> Option<usize> {
+    (0..NUM_OF_WATCHPOINTS).find(|&i| dr7 & local_enable_bit(i) == 0)
+}
+
+/// Watches the address on the current CPU. Returns the index of the debug register used.
+pub fn set_watchpoint(watchpoint: Watchpoint) -> Result<usize> {
+    let dr7 = read_dr7();
+    let index = find_free_slot(dr7).ok_or("All debug registers are in use")?;
+    let bits = watchpoint.dr7_bits(index)?;
+    unsafe {
+        write_debug_address_register(index, watchpoint.addr);
+        write_dr7(dr7 & !dr7_mask(index) | bits);
+    }
+    Ok(index)
+}
+pub fn clear_watchpoint(index: usize) -> Result<()> {
+    if index >= NUM_OF_WATCHPOINTS {
+        return Err("Invalid watchpoint index");
+    }
+    let dr7 = read_dr7();
+    if dr7 & local_enable_bit(index) == 0 {
+        return Err("Watchpoint is not set");
+    }
+    unsafe { write_dr7(dr7 & !dr7_mask(index)) };
+    Ok(())
+}
+pub fn watchpoint(index: usize) -> Option<Watchpoint> {
+    if index >= NUM_OF_WATCHPOINTS {
+        return None;
+    }
+    Watchpoint::from_dr7(read_dr7(), index, read_debug_address_register(index))
+}
+
+/// Reports the watchpoints that raised #DB and clears DR6.
+/// Returns false if #DB was not raised by a watchpoint (e.g. single-step).
+pub fn report_watchpoint_hit(info: &mut InterruptInfo) -> bool {
+    let dr6 = read_dr6();
+    unsafe { write_dr6(DR6_CLEAR) };
+    let rip = *info.register_mut(Register::Rip);
+    let rbp = *info.register_mut(Register::Rbp);
+    let mut is_hit = false;
+    // DR6 can also report the conditions of disabled breakpoints.
+    let hits = (0..NUM_OF_WATCHPOINTS)
+        .filter(|i| dr6 & DR6_HIT_MASK & (1 << i) != 0)
+        .filter_map(|i| Some((i, watchpoint(i)?)));
+    for (index, watchpoint) in hits {
+        warn!("Watchpoint {index} hit: {watchpoint}, RIP={rip:#018X}");
+        if watchpoint.kind == WatchpointKind::Execute {
+            // #DB is a fault for execution breakpoints. Skip the breakpoint
+            // once so that the instruction can be executed after returning.
+            *info.register_mut(Register::Rflags) |= RFLAGS_RF;
+        }
+        is_hit = true;
+    }
+    if is_hit {
+        NUM_OF_HITS.fetch_add(1, Ordering::SeqCst);
+        Backtrace::from_rbp(Some(rip), rbp).print();
+    }
+    is_hit
+}
+/// Number of #DB reported by report_watchpoint_hit() so far
+pub fn num_of_watchpoint_hits() -> u64 {
+    NUM_OF_HITS.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use crate::interrupt::register_irq_handler;
+    use crate::interrupt::unregister_irq_handler;
+    use crate::interrupt::TEST_VECTOR;
+    use alloc::format;
+    use core::arch::asm;
+
+    #[test_case]
+    fn watchpoints_are_encoded_in_dr7() {
+        let wp = Watchpoint::write(0x1000, 8);
+        let bits = wp.dr7_bits(1).unwrap();
+        assert_eq!(bits, 1 << 2 | 0b1001 << 20);
+        assert_eq!(Watchpoint::from_dr7(bits, 1, 0x1000), Some(wp));
+        assert_eq!(Watchpoint::from_dr7(bits, 0, 0x1000), None);
+        let wp = Watchpoint::read_write(0x2002, 2);
+        let bits = wp.dr7_bits(3).unwrap();
+        assert_eq!(bits, 1 << 6 | 0b0111 << 28);
+        assert_eq!(Watchpoint::from_dr7(bits, 3, 0x2002), Some(wp));
+        let wp = Watchpoint::execute(0x3001);
+        assert_eq!(wp.dr7_bits(0), Ok(1));
+        assert_eq!(Watchpoint::from_dr7(1, 0, 0x3001), Some(wp));
+        assert_eq!(
+            Watchpoint::from_dr7(Watchpoint::write(0, 4).dr7_bits(2).unwrap(), 2, 0)
+                .unwrap()
+                .len,
+            4
+        );
+        assert!(Watchpoint::write(0x1004, 8).dr7_bits(0).is_err());
+        assert!(Watchpoint::write(0x1000, 3).dr7_bits(0).is_err());
+        assert!(Watchpoint {
+            addr: 0x1000,
+            kind: WatchpointKind::Execute,
+            len: 4
+        }
+        .dr7_bits(0)
+        .is_err());
+        // Slots are found from the enable bits
+        assert_eq!(find_free_slot(0), Some(0));
+        assert_eq!(find_free_slot(0b0101), Some(2));
+        assert_eq!(find_free_slot(0b0101_0101), None);
+        assert_eq!(dr7_mask(2) & dr7_mask(3), 0);
+        assert_eq!(
+            format!("{}", Watchpoint::write(0x10, 4)),
+            "write to 0x0000000000000010 (4 bytes)"
+        );
+    }
+
+    static WATCHED: AtomicU64 = AtomicU64::new(0);
+    fn write_to_watched(_: u8, _: &mut InterruptInfo) {
+        WATCHED.store(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn watchpoint_hit_in_interrupt_handler_is_reported() {
+        // #DB is raised while the handler is running on the IRQ stack
+        register_irq_handler(TEST_VECTOR, write_to_watched).unwrap();
+        let index = set_watchpoint(Watchpoint::write(WATCHED.as_ptr() as u64, 8)).unwrap();
+        let hits = num_of_watchpoint_hits();
+        unsafe { asm!("int 0x2F") };
+        assert_eq!(num_of_watchpoint_hits(), hits + 1);
+        assert_eq!(WATCHED.load(Ordering::SeqCst), 1);
+        clear_watchpoint(index).unwrap();
+        unregister_irq_handler(TEST_VECTOR).unwrap();
+    }
+}
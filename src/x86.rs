@@ -1,11 +1,29 @@
 extern crate alloc;
 
+use crate::address_space::phys_to_virt;
+use crate::apic::send_eoi;
+use crate::apic::IRQ_VECTOR_BASE;
+use crate::apic::SPURIOUS_VECTOR;
+use crate::backtrace::set_fault_frame;
 use crate::error;
+use crate::exception::exception_name;
+use crate::exception::Mxcsr;
+use crate::exception::PageFaultErrorCode;
+use crate::exception::SelectorErrorCode;
+use crate::frame_allocator::alloc_frame_4k;
+use crate::frame_allocator::free_frame_4k;
 use crate::info;
+use crate::interrupt::irq_handler;
 use crate::result::Result;
+use crate::stack::alloc_kernel_stack;
+use crate::stack::find_overflowed_stack;
+use crate::vma::find_vma;
+use crate::vma::handle_page_fault;
+use crate::watchpoint::report_watchpoint_hit;
 use alloc::boxed::Box;
 use core::arch::asm;
 use core::arch::global_asm;
+use core::arch::x86_64::__cpuid;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem::offset_of;
@@ -13,6 +31,7 @@ use core::mem::size_of;
 use core::mem::size_of_val;
 use core::mem::MaybeUninit;
 use core::pin::Pin;
+use core::ptr::write_bytes;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -22,6 +41,73 @@ pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
 
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi) }
+    (hi as u64) << 32 | lo as u64
+}
+
+pub fn read_rbp() -> u64 {
+    let mut rbp: u64;
+    unsafe { asm!("mov rax, rbp", out("rax") rbp) }
+    rbp
+}
+
+/// Calls f on the stack whose top is stack_top, and switches back to the current stack after it returns.
+///
+/// # Safety
+/// stack_top should be 16-byte aligned and the stack should be large enough for f.
+pub unsafe fn call_on_stack(stack_top: u64, f: extern "sysv64" fn()) {
+    // RSP is saved in R12 since it is preserved by f.
+    asm!(
+        "mov r12, rsp",
+        "mov rsp, {stack_top}",
+        "call {f}",
+        "mov rsp, r12",
+        stack_top = in(reg) stack_top,
+        f = in(reg) f,
+        out("r12") _,
+        clobber_abi("sysv64"),
+    )
+}
+
+pub const RFLAGS_INTERRUPT_FLAG: u64 = 1 << 9;
+
+pub fn is_1g_page_supported() -> bool {
+    // CPUID.80000001H:EDX[26] (Page1GB)
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+pub fn is_x2apic_supported() -> bool {
+    // CPUID.01H:ECX[21] (x2APIC)
+    unsafe { __cpuid(1) }.ecx & (1 << 21) != 0
+}
+
+pub fn is_tsc_deadline_supported() -> bool {
+    // CPUID.01H:ECX[24] (TSC-Deadline)
+    unsafe { __cpuid(1) }.ecx & (1 << 24) != 0
+}
+
+pub fn read_rflags() -> u64 {
+    let mut rflags: u64;
+    unsafe { asm!("pushfq", "pop rax", out("rax") rflags) }
+    rflags
+}
+
+pub fn are_interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_INTERRUPT_FLAG != 0
+}
+
+pub fn disable_interrupts() {
+    unsafe { asm!("cli") }
+}
+
+pub fn enable_interrupts() {
+    unsafe { asm!("sti") }
+}
+
 pub fn read_io_port_u8(port: u16) -> u8 {
     let mut data: u8;
     unsafe { asm!("in al, dx", out("al") data, in("dx") port) }
@@ -47,18 +133,118 @@ pub fn read_cr3() -> *mut PML4 {
 }
 
 pub const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE_2M: usize = 2 * 1024 * 1024;
+pub const PAGE_SIZE_1G: usize = 1024 * 1024 * 1024;
 const ATTR_MASK: u64 = 0xFFF;
 const ATTR_PRESENT: u64 = 1 << 0;
 const ATTR_WRITABLE: u64 = 1 << 1;
+const ATTR_USER: u64 = 1 << 2;
 const ATTR_WRITE_THROUGH: u64 = 1 << 3;
 const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+const ATTR_ACCESSED: u64 = 1 << 5;
+const ATTR_DIRTY: u64 = 1 << 6;
+const ATTR_PAGE_SIZE: u64 = 1 << 7;
+// PAT bit of the entries in PT. It is at bit 12 for the huge pages
+// since bit 7 is used for ATTR_PAGE_SIZE in PD and PDPT.
+const ATTR_PAT: u64 = 1 << 7;
+const ATTR_PAT_HUGE: u64 = 1 << 12;
+const ATTR_GLOBAL: u64 = 1 << 8;
+// Requires EFER.NXE (see enable_page_attributes())
+const ATTR_NO_EXECUTE: u64 = 1 << 63;
+const PHYS_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+// PAT, PCD and PWT select the memory type from IA32_PAT. See IA32_PAT_VALUE.
+const ATTR_WRITE_COMBINING: u64 = ATTR_PAT;
 
-#[derive(Debug, Copy, Clone)]
+/// Attributes for the leaf entries.
+/// Kernel mappings other than ReadWriteKernel and ReadWriteNoExecuteLocalKernel
+/// are global since they are shared by all the address spaces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u64)]
 pub enum PageAttr {
     NotPresent = 0,
     ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
     ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+    ReadOnlyKernel = ATTR_PRESENT | ATTR_GLOBAL | ATTR_NO_EXECUTE,
+    ReadExecuteKernel = ATTR_PRESENT | ATTR_GLOBAL,
+    ReadWriteNoExecuteKernel = ATTR_PRESENT | ATTR_WRITABLE | ATTR_GLOBAL | ATTR_NO_EXECUTE,
+    /// For the identity map in the lower half, which is not shared with the other address spaces
+    ReadWriteNoExecuteLocalKernel = ATTR_PRESENT | ATTR_WRITABLE | ATTR_NO_EXECUTE,
+    /// For frame buffers
+    WriteCombiningKernel =
+        ATTR_PRESENT | ATTR_WRITABLE | ATTR_GLOBAL | ATTR_NO_EXECUTE | ATTR_WRITE_COMBINING,
+    ReadOnlyUser = ATTR_PRESENT | ATTR_USER | ATTR_NO_EXECUTE,
+    ReadExecuteUser = ATTR_PRESENT | ATTR_USER,
+    ReadWriteNoExecuteUser = ATTR_PRESENT | ATTR_WRITABLE | ATTR_USER | ATTR_NO_EXECUTE,
+}
+impl PageAttr {
+    fn is_user(self) -> bool {
+        self as u64 & ATTR_USER != 0
+    }
+}
+
+const MSR_IA32_EFER: u32 = 0xC000_0080;
+const MSR_IA32_PAT: u32 = 0x277;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: u64 = 1 << 16;
+const CR4_PGE: u64 = 1 << 7;
+// Memory types for PAT entry 0-7:
+// 0: WB, 1: WT, 2: UC-, 3: UC (same as the power-on default)
+// 4: WC (default: WB), 5: WT, 6: UC-, 7: UC
+const IA32_PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+pub fn read_msr(index: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe { asm!("rdmsr", in("ecx") index, out("eax") lo, out("edx") hi) }
+    (hi as u64) << 32 | lo as u64
+}
+/// # Safety
+/// Writing to MSRs can change the behavior of the CPU in any way.
+pub unsafe fn write_msr(index: u32, value: u64) {
+    asm!("wrmsr", in("ecx") index, in("eax") value as u32, in("edx") (value >> 32) as u32)
+}
+pub fn read_cr0() -> u64 {
+    let mut cr0: u64;
+    unsafe { asm!("mov rax, cr0", out("rax") cr0) }
+    cr0
+}
+/// # Safety
+/// Changing CR0 can break the memory protection and caching.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, rax", in("rax") cr0)
+}
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe { asm!("mov rax, cr4", out("rax") cr4) }
+    cr4
+}
+/// # Safety
+/// Changing CR4 can enable features that the page tables are not ready for.
+pub unsafe fn write_cr4(cr4: u64) {
+    asm!("mov cr4, rax", in("rax") cr4)
+}
+pub fn is_nx_supported() -> bool {
+    // CPUID.80000001H:EDX[20] (NX)
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0
+}
+
+/// Makes the CPU honor all the bits used by PageAttr:
+/// no-execute (EFER.NXE), read-only pages in the kernel (CR0.WP),
+/// global pages (CR4.PGE) and write-combining (PAT entry 4).
+/// This should be called before loading page tables that use them.
+pub fn enable_page_attributes() -> Result<()> {
+    if !is_nx_supported() {
+        return Err("NX bit is not supported");
+    }
+    unsafe {
+        write_msr(MSR_IA32_EFER, read_msr(MSR_IA32_EFER) | EFER_NXE);
+        write_msr(MSR_IA32_PAT, IA32_PAT_VALUE);
+        write_cr0(read_cr0() | CR0_WP);
+        write_cr4(read_cr4() | CR4_PGE);
+    }
+    flush_tlb();
+    Ok(())
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -86,29 +272,65 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
     fn is_user(&self) -> bool {
         (self.read_value() & (1 << 2)) != 0
     }
+    fn is_leaf(&self) -> bool {
+        LEVEL == 1 || self.is_huge_page()
+    }
+    /// Returns true if this entry maps a page directly instead of pointing a next table.
+    /// Valid only for the entries in PDPT (1GiB page) and PD (2MiB page).
+    fn is_huge_page(&self) -> bool {
+        (self.read_value() & ATTR_PAGE_SIZE) != 0
+    }
+    fn has_table(&self) -> bool {
+        self.is_present() && !self.is_huge_page()
+    }
+    /// Physical address of virt in the page mapped by this entry
+    fn page_phys(&self, virt: u64) -> u64 {
+        let offset_mask = (1u64 << SHIFT) - 1;
+        (self.read_value() & PHYS_ADDR_MASK & !offset_mask) | (virt & offset_mask)
+    }
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "L{}Entry @ {:#p} {{ {:#018X} {}{}{} ",
+            "L{}Entry @ {:#p} {{ {:#018X} {}{}{}{}",
             LEVEL,
             self,
             self.read_value(),
             if self.is_present() { "P" } else { "N" },
             if self.is_writable() { "W" } else { "R" },
-            if self.is_user() { "U" } else { "S" }
+            if self.is_user() { "U" } else { "S" },
+            if self.read_value() & ATTR_NO_EXECUTE != 0 {
+                "-"
+            } else {
+                "X"
+            },
         )?;
+        let pat = if LEVEL == 1 { ATTR_PAT } else { ATTR_PAT_HUGE };
+        let flags = [
+            (ATTR_WRITE_THROUGH, "PWT"),
+            (ATTR_CACHE_DISABLE, "PCD"),
+            (ATTR_ACCESSED, "A"),
+            (ATTR_DIRTY, "D"),
+            (if LEVEL == 1 { 0 } else { ATTR_PAGE_SIZE }, "PS"),
+            (ATTR_GLOBAL, "G"),
+            (if self.is_leaf() { pat } else { 0 }, "PAT"),
+        ];
+        for (bit, name) in flags {
+            if self.read_value() & bit != 0 {
+                write!(f, " {name}")?;
+            }
+        }
         write!(f, " }}")
     }
     fn table(&self) -> Result<&NEXT> {
         if self.is_present() {
-            Ok(unsafe { &*((self.value & !ATTR_MASK) as *const NEXT) })
+            Ok(unsafe { &*(phys_to_virt(self.value & PHYS_ADDR_MASK) as *const NEXT) })
         } else {
             Err("Page Not Found")
         }
     }
     fn table_mut(&mut self) -> Result<&mut NEXT> {
         if self.is_present() {
-            Ok(unsafe { &mut *((self.value & !ATTR_MASK) as *mut NEXT) })
+            Ok(unsafe { &mut *(phys_to_virt(self.value & PHYS_ADDR_MASK) as *mut NEXT) })
         } else {
             Err("Page Not Found")
         }
@@ -125,8 +347,10 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
         if self.is_present() {
             Err("Page is already populated")
         } else {
-            let next: Box<NEXT> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
-            self.value = Box::into_raw(next) as u64 | PageAttr::ReadWriteKernel as u64;
+            // Entries filled with 0 are valid (not present) for any level of the table.
+            let next = alloc_frame_4k()?;
+            unsafe { write_bytes(phys_to_virt(next) as *mut u8, 0, PAGE_SIZE) };
+            self.value = next | PageAttr::ReadWriteKernel as u64;
             Ok(self)
         }
     }
@@ -137,6 +361,66 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
             self.populate()
         }
     }
+    /// Maps a (1 << SHIFT)-byte page with this entry.
+    /// Should be used only for the entries in PDPT and PD.
+    fn set_huge_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
+        if phys & ((1 << SHIFT) - 1) != 0 {
+            Err("phys is not aligned")
+        } else if matches!(attr, PageAttr::NotPresent) {
+            self.value = 0;
+            Ok(())
+        } else {
+            let mut attr = attr as u64;
+            if attr & ATTR_PAT != 0 {
+                attr = (attr & !ATTR_PAT) | ATTR_PAT_HUGE;
+            }
+            self.value = phys | attr | ATTR_PAGE_SIZE;
+            Ok(())
+        }
+    }
+    /// Replaces the huge page mapped by this entry with a next level table
+    /// that maps the same range with the same attributes.
+    fn split_huge_page(&mut self) -> Result<()> {
+        let next = alloc_frame_4k()?;
+        let phys = self.page_phys(0);
+        // Bits other than the address. ATTR_PAT_HUGE is in the address field of PT entries.
+        let mut attr = self.read_value() & !(PHYS_ADDR_MASK & !ATTR_PAT_HUGE);
+        if LEVEL == 2 {
+            // Entries in PT do not have the PS bit, and have the PAT bit at bit 7.
+            attr &= !ATTR_PAGE_SIZE;
+            if attr & ATTR_PAT_HUGE != 0 {
+                attr = (attr & !ATTR_PAT_HUGE) | ATTR_PAT;
+            }
+        }
+        let child_size = 1u64 << (SHIFT - 9);
+        let entries =
+            unsafe { core::slice::from_raw_parts_mut(phys_to_virt(next) as *mut u64, 512) };
+        for (i, e) in entries.iter_mut().enumerate() {
+            *e = (phys + i as u64 * child_size) | attr;
+        }
+        self.value = next | PageAttr::ReadWriteKernel as u64 | (attr & ATTR_USER);
+        Ok(())
+    }
+    /// Clears this entry and returns the frame of the next level table to the frame allocator.
+    fn free_table(&mut self) -> Result<()> {
+        let table = self.read_value() & PHYS_ADDR_MASK;
+        self.value = 0;
+        free_frame_4k(table)
+    }
+    /// Returns the next level table, creating it if needed.
+    /// If this entry maps a huge page, it is split into smaller pages.
+    /// attr is the attribute of the leaf entry that will be set under this entry.
+    fn ensure_table(&mut self, attr: PageAttr) -> Result<&mut NEXT> {
+        if self.is_present() && self.is_huge_page() {
+            self.split_huge_page()?;
+        }
+        self.ensure_populated()?;
+        if attr.is_user() {
+            // User access is checked at every level of the tables.
+            self.value |= ATTR_USER;
+        }
+        self.table_mut()
+    }
 }
 impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -172,6 +456,9 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> Table<LEVEL
     fn calc_index(&self, addr: u64) -> usize {
         ((addr >> SHIFT) & 0b1_1111_1111) as usize
     }
+    fn is_empty(&self) -> bool {
+        self.entry.iter().all(|e| !e.is_present())
+    }
 }
 impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> fmt::Debug
     for Table<LEVEL, SHIFT, NEXT>
@@ -210,19 +497,164 @@ impl PML4 {
         if phys & ATTR_MASK != 0 {
             return Err("Invalid phys");
         }
-        for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
+        let use_1g_page = is_1g_page_supported();
+        let mut addr = virt_start;
+        while addr < virt_end {
+            let phys_addr = phys + addr - virt_start;
+            // Huge pages are used only if both of virt and phys are aligned
+            // and the page does not go beyond virt_end.
+            let can_map = |page_size: usize| {
+                let page_size = page_size as u64;
+                addr % page_size == 0 && phys_addr % page_size == 0 && virt_end - addr >= page_size
+            };
             let index = self.calc_index(addr);
-            let table = self.entry[index].ensure_populated()?.table_mut()?;
-            let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
-            let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
-            let index = table.calc_index(addr);
-            let pte = &mut table.entry[index];
-            pte.set_page(phys + addr - virt_start, attr)?;
+            let pdpt = self.entry[index].ensure_table(attr)?;
+            let index = pdpt.calc_index(addr);
+            let pdpte = &mut pdpt.entry[index];
+            // Entries that point a table are not replaced with a huge page
+            // to avoid leaking the tables under them.
+            if use_1g_page && can_map(PAGE_SIZE_1G) && !pdpte.has_table() {
+                pdpte.set_huge_page(phys_addr, attr)?;
+                addr += PAGE_SIZE_1G as u64;
+                continue;
+            }
+            let pd = pdpte.ensure_table(attr)?;
+            let index = pd.calc_index(addr);
+            let pde = &mut pd.entry[index];
+            if can_map(PAGE_SIZE_2M) && !pde.has_table() {
+                pde.set_huge_page(phys_addr, attr)?;
+                addr += PAGE_SIZE_2M as u64;
+                continue;
+            }
+            let pt = pde.ensure_table(attr)?;
+            let index = pt.calc_index(addr);
+            let pte = &mut pt.entry[index];
+            pte.set_page(phys_addr, attr)?;
+            addr += PAGE_SIZE as u64;
         }
         Ok(())
     }
+    /// Removes the mappings in [virt_start, virt_end) and frees the tables that become empty.
+    /// Huge pages that are partially in the range are split before unmapping.
+    /// All the tables under self should be allocated from the frame allocator.
+    pub fn unmap(&mut self, virt_start: u64, virt_end: u64) -> Result<()> {
+        if virt_start & ATTR_MASK != 0 {
+            return Err("Invalid virt_start");
+        }
+        if virt_end & ATTR_MASK != 0 {
+            return Err("Invalid virt_end");
+        }
+        let mut addr = virt_start;
+        while addr < virt_end {
+            let prev = addr;
+            addr = self.unmap_page(addr, virt_end)?;
+            // Check the tables only when leaving a PT, to keep this O(n).
+            if addr >= virt_end || addr % PAGE_SIZE_2M as u64 == 0 {
+                self.free_empty_tables(prev)?;
+            }
+        }
+        Ok(())
+    }
+    /// Unmaps the page at addr, or skips the range that has no table.
+    /// Returns the address to be processed next.
+    fn unmap_page(&mut self, addr: u64, virt_end: u64) -> Result<u64> {
+        let covers = |page_size: usize| {
+            let page_size = page_size as u64;
+            addr % page_size == 0 && virt_end - addr >= page_size
+        };
+        let next_boundary = |size: u64| (addr | (size - 1)).saturating_add(1);
+        let pml4e = &mut self.entry[self.calc_index(addr)];
+        if !pml4e.is_present() {
+            return Ok(next_boundary(1 << 39));
+        }
+        let pdpt = pml4e.table_mut()?;
+        let pdpte = &mut pdpt.entry[pdpt.calc_index(addr)];
+        if !pdpte.is_present() {
+            return Ok(next_boundary(PAGE_SIZE_1G as u64));
+        }
+        if pdpte.is_huge_page() {
+            if covers(PAGE_SIZE_1G) {
+                pdpte.value = 0;
+                invlpg(addr);
+                return Ok(addr + PAGE_SIZE_1G as u64);
+            }
+            pdpte.split_huge_page()?;
+        }
+        let pd = pdpte.table_mut()?;
+        let pde = &mut pd.entry[pd.calc_index(addr)];
+        if !pde.is_present() {
+            return Ok(next_boundary(PAGE_SIZE_2M as u64));
+        }
+        if pde.is_huge_page() {
+            if covers(PAGE_SIZE_2M) {
+                pde.value = 0;
+                invlpg(addr);
+                return Ok(addr + PAGE_SIZE_2M as u64);
+            }
+            pde.split_huge_page()?;
+        }
+        let pt = pde.table_mut()?;
+        let pte = &mut pt.entry[pt.calc_index(addr)];
+        if pte.is_present() {
+            pte.value = 0;
+            invlpg(addr);
+        }
+        Ok(addr + PAGE_SIZE as u64)
+    }
+    /// Frees the PT, PD and PDPT used to map virt if they have no present entries.
+    fn free_empty_tables(&mut self, virt: u64) -> Result<()> {
+        let pml4e = &mut self.entry[self.calc_index(virt)];
+        let Ok(pdpt) = pml4e.table_mut() else {
+            return Ok(());
+        };
+        let pdpte = &mut pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.has_table() {
+            let pd = pdpte.table_mut()?;
+            let pde = &mut pd.entry[pd.calc_index(virt)];
+            if pde.has_table() && pde.table()?.is_empty() {
+                pde.free_table()?;
+            }
+            if pd.is_empty() {
+                pdpte.free_table()?;
+            }
+        }
+        if pdpt.is_empty() {
+            pml4e.free_table()?;
+        }
+        Ok(())
+    }
+    /// Walks the page tables to find the physical address that virt is mapped to.
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let pml4e = &self.entry[self.calc_index(virt)];
+        let pdpt = pml4e.table().or(Err("PML4 entry is not present"))?;
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if !pdpte.is_present() {
+            return Err("PDPT entry is not present");
+        }
+        if pdpte.is_huge_page() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: pdpte.page_phys(virt),
+            });
+        }
+        let pd = pdpte.table()?;
+        let pde = &pd.entry[pd.calc_index(virt)];
+        if !pde.is_present() {
+            return Err("PD entry is not present");
+        }
+        if pde.is_huge_page() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: pde.page_phys(virt),
+            });
+        }
+        let pt = pde.table()?;
+        let pte = &pt.entry[pt.calc_index(virt)];
+        if !pte.is_present() {
+            return Err("PT entry is not present");
+        }
+        Ok(TranslationResult::PageMapped4K {
+            phys: pte.page_phys(virt),
+        })
+    }
 }
 
 /// # Safety
@@ -306,7 +738,7 @@ const _: () = assert!(size_of::<InterruptContext>() == 8 * 5);
 #[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct InterruptInfo {
+pub struct InterruptInfo {
     // This struct is placed at top of the interrupt stack.
     // Should be aligned on 16-byte boundaries to pass the
     // alignment checks done by FXSAVE / FXRSTOR
@@ -317,6 +749,64 @@ struct InterruptInfo {
     ctx: InterruptContext,
 }
 const _: () = assert!(size_of::<InterruptInfo>() == (16 + 4 + 1) * 8 + 8 + 512);
+/// Registers of the interrupted context, which are restored on return from the handler
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Rip,
+    Rflags,
+    Cs,
+    Ss,
+}
+pub const RFLAGS_TF: u64 = 1 << 8;
+/// Suppresses instruction breakpoints for the next instruction
+pub const RFLAGS_RF: u64 = 1 << 16;
+impl InterruptInfo {
+    fn mxcsr(&self) -> u32 {
+        // MXCSR is at offset 24 of the FXSAVE area
+        let mxcsr = &self.fpu_context.data[24..28];
+        u32::from_le_bytes([mxcsr[0], mxcsr[1], mxcsr[2], mxcsr[3]])
+    }
+    pub fn register_mut(&mut self, reg: Register) -> &mut u64 {
+        match reg {
+            Register::Rax => &mut self.greg.rax,
+            Register::Rbx => &mut self.greg.rbx,
+            Register::Rcx => &mut self.greg.rcx,
+            Register::Rdx => &mut self.greg.rdx,
+            Register::Rsi => &mut self.greg.rsi,
+            Register::Rdi => &mut self.greg.rdi,
+            Register::Rbp => &mut self.greg.rbp,
+            Register::Rsp => &mut self.ctx.rsp,
+            Register::R8 => &mut self.greg.r8,
+            Register::R9 => &mut self.greg.r9,
+            Register::R10 => &mut self.greg.r10,
+            Register::R11 => &mut self.greg.r11,
+            Register::R12 => &mut self.greg.r12,
+            Register::R13 => &mut self.greg.r13,
+            Register::R14 => &mut self.greg.r14,
+            Register::R15 => &mut self.greg.r15,
+            Register::Rip => &mut self.ctx.rip,
+            Register::Rflags => &mut self.ctx.rflags,
+            Register::Cs => &mut self.ctx.cs,
+            Register::Ss => &mut self.ctx.ss,
+        }
+    }
+}
 impl fmt::Debug for InterruptInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -370,66 +860,44 @@ impl fmt::Debug for InterruptInfo {
 // In IA-32e mode, the RSP is aligned to a 16-byte boundary
 // before pushing the stack frame
 
-/// This generates interrupt_entrypointN()
-/// Generated asm will be looks like this:
-/// ```
-/// .global interrupt_entrypointN
-///   interrupt_entrypointN:
-///   push 0 // No error code
-///   push rcx // Save rcx first to reuse
-///   mov rcx, N // INT#
-///   jmp inthandler_common
-/// ```
-macro_rules! interrupt_entrypoint {
-    ($index:literal) => {
-        global_asm!(concat!(
-            ".global interrupt_entrypoint",
-            stringify!($index),
-            "\n",
-            "interrupt_entrypoint",
-            stringify!($index),
-            ":\n",
-            "push 0 // No error code\n",
-            "push rcx // Save rcx first to reuse\n",
-            "mov rcx, ",
-            stringify!($index),
-            "\n",
-            "jmp inthandler_common"
-        ));
-    };
-}
-macro_rules! interrupt_entrypoint_with_ecode {
-    ($index:literal) => {
-        global_asm!(concat!(
-            ".global interrupt_entrypoint",
-            stringify!($index),
-            "\n",
-            "interrupt_entrypoint",
-            stringify!($index),
-            ":\n",
-            "push rcx // Save rcx first to reuse\n",
-            "mov rcx, ",
-            stringify!($index),
-            "\n",
-            "jmp inthandler_common"
-        ));
-    };
-}
-
-interrupt_entrypoint!(3);
-interrupt_entrypoint!(6);
-interrupt_entrypoint_with_ecode!(8);
-interrupt_entrypoint_with_ecode!(13);
-interrupt_entrypoint_with_ecode!(14);
-interrupt_entrypoint!(32);
+// Size of each entry point in interrupt_entrypoints
+const INTERRUPT_ENTRYPOINT_SIZE: usize = 16;
+
+// This generates interrupt_entrypoints, which has the entry points of all
+// the 256 vectors placed every INTERRUPT_ENTRYPOINT_SIZE bytes.
+// The CPU pushes an error code only for the vectors 8, 10-14, 17, 21, 29 and 30
+// (SDM Vol.3 6.15), so the others push a dummy one to have the same stack layout.
+// Each of them looks like this:
+//   push 0 // No error code (omitted if the CPU pushes one)
+//   push rcx // Save rcx first to reuse
+//   mov rcx, N // INT#
+//   jmp inthandler_common
+global_asm!(
+    r#"
+.global interrupt_entrypoints
+.p2align 4
+interrupt_entrypoints:
+.set interrupt_vector, 0
+.rept 256
+    .p2align 4
+    .if interrupt_vector == 8 || (interrupt_vector >= 10 && interrupt_vector <= 14) || interrupt_vector == 17 || interrupt_vector == 21 || interrupt_vector == 29 || interrupt_vector == 30
+    .else
+    push 0 // No error code
+    .endif
+    push rcx // Save rcx first to reuse
+    mov ecx, offset interrupt_vector
+    jmp inthandler_common
+    .set interrupt_vector, interrupt_vector + 1
+.endr
+"#
+);
 
 extern "sysv64" {
-    fn interrupt_entrypoint3();
-    fn interrupt_entrypoint6();
-    fn interrupt_entrypoint8();
-    fn interrupt_entrypoint13();
-    fn interrupt_entrypoint14();
-    fn interrupt_entrypoint32();
+    fn interrupt_entrypoints();
+}
+fn interrupt_entrypoint(vector: usize) -> u64 {
+    interrupt_entrypoints as *const unsafe extern "sysv64" fn() as u64
+        + (vector * INTERRUPT_ENTRYPOINT_SIZE) as u64
 }
 
 global_asm!(
@@ -496,23 +964,97 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+/// Reads the address in DR0-DR3
+pub fn read_debug_address_register(index: usize) -> u64 {
+    let mut value: u64;
+    unsafe {
+        match index {
+            0 => asm!("mov rax, dr0", out("rax") value),
+            1 => asm!("mov rax, dr1", out("rax") value),
+            2 => asm!("mov rax, dr2", out("rax") value),
+            3 => asm!("mov rax, dr3", out("rax") value),
+            _ => panic!("DR{index} is not an address register"),
+        }
+    }
+    value
+}
+/// # Safety
+/// The address is watched once it is enabled in DR7.
+pub unsafe fn write_debug_address_register(index: usize, value: u64) {
+    match index {
+        0 => asm!("mov dr0, rax", in("rax") value),
+        1 => asm!("mov dr1, rax", in("rax") value),
+        2 => asm!("mov dr2, rax", in("rax") value),
+        3 => asm!("mov dr3, rax", in("rax") value),
+        _ => panic!("DR{index} is not an address register"),
+    }
+}
+pub fn read_dr6() -> u64 {
+    let mut dr6: u64;
+    unsafe { asm!("mov rax, dr6", out("rax") dr6) }
+    dr6
+}
+/// # Safety
+/// The status bits in DR6 are never cleared by the CPU.
+pub unsafe fn write_dr6(dr6: u64) {
+    asm!("mov dr6, rax", in("rax") dr6)
+}
+pub fn read_dr7() -> u64 {
+    let mut dr7: u64;
+    unsafe { asm!("mov rax, dr7", out("rax") dr7) }
+    dr7
+}
+/// # Safety
+/// Enabling a breakpoint raises #DB on the access to the address in DR0-DR3.
+pub unsafe fn write_dr7(dr7: u64) {
+    asm!("mov dr7, rax", in("rax") dr7)
+}
+
 #[no_mangle]
-extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+extern "sysv64" fn inthandler(info: &mut InterruptInfo, index: usize) {
+    if index == 14 && handle_page_fault(read_cr2(), info.error_code).is_ok() {
+        // The page is backed by a VMA now. Retry the instruction.
+        return;
+    }
+    let is_irq = index >= IRQ_VECTOR_BASE as usize;
+    // Spurious interrupts are not counted as in-service by the Local APIC
+    if index == SPURIOUS_VECTOR as usize {
+        return;
+    }
+    if let Some(handler) = irq_handler(index as u8) {
+        handler(index as u8, info);
+        if is_irq {
+            send_eoi();
+        }
+        return;
+    }
+    if is_irq {
+        error!("Unexpected interrupt {index:#04X}");
+        send_eoi();
+        return;
+    }
+    if index == 1 && report_watchpoint_hit(info) {
+        return;
+    }
     error!("Interrupt Info: {:?}", info);
-    error!("Exception {index:#04X}: ");
+    let name = exception_name(index).expect("vector should be an exception here");
+    error!("Exception {index:#04X}: {name}");
     match index {
         3 => {
-            error!("Breakpoint");
             return;
         }
-        6 => {
-            error!("Invalid Opcode");
-        }
         8 => {
-            error!("Double Fault");
+            // An overflow of a stack makes the #PF handler overflow it again,
+            // which escalates to #DF that runs on its own IST stack.
+            if let Some(name) = find_overflowed_stack(&[read_cr2(), info.ctx.rsp]) {
+                error!("stack overflow on stack {name}");
+            }
+        }
+        10..=12 => {
+            error!("Error code: {}", SelectorErrorCode(info.error_code));
         }
         13 => {
-            error!("General Protection Fault");
+            error!("Error code: {}", SelectorErrorCode(info.error_code));
             let rip = info.ctx.rip;
             error!("Bytes @ RIP({rip:#018X}):");
             let rip = rip as *const u8;
@@ -520,46 +1062,23 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
             error!(" = {bytes:02X?}");
         }
         14 => {
-            error!("Page Fault");
-            error!("CR2={:#018X}", read_cr2());
-            error!(
-                "Caused by: A {} mode {} on a {} page, page structures are {}",
-                if info.error_code & 0b0000_0100 != 0 {
-                    "user"
-                } else {
-                    "supervisor"
-                },
-                if info.error_code & 0b0001_0000 != 0 {
-                    "instruction fetch"
-                } else if info.error_code & 0b0010 != 0 {
-                    "data write"
-                } else {
-                    "data read"
-                },
-                if info.error_code & 0b0001 != 0 {
-                    "present"
-                } else {
-                    "non-present"
-                },
-                if info.error_code & 0b1000 != 0 {
-                    "invalid"
-                } else {
-                    "valid"
-                },
-            );
-        }
-        _ => {
-            error!("Not handled");
+            let cr2 = read_cr2();
+            error!("CR2={cr2:#018X}");
+            if let Some(vma) = find_vma(cr2) {
+                error!("CR2 is in VMA {:?} {:#018X?}", vma.name(), vma.range());
+            }
+            error!("Caused by: {}", PageFaultErrorCode(info.error_code));
         }
+        19 => {
+            error!("MXCSR: {}", Mxcsr(info.mxcsr()));
+        }
+        _ => {}
     }
+    // The backtrace is printed by the panic handler
+    set_fault_frame(info.ctx.rip, info.greg.rbp);
     panic!("fatal exception");
 }
 
-#[no_mangle]
-extern "sysv64" fn int_handler_unimplemented() {
-    panic!("unexpected interrupt!");
-}
-
 // PDDRTTTT (TTTT: type, R: reserved, D: DPL, P: present)
 pub const BIT_FLAGS_INTGATE: u8 = 0b0000_1110u8;
 pub const BIT_FLAGS_PRESENT: u8 = 0b1000_0000u8;
@@ -590,13 +1109,7 @@ pub struct IdtDescriptor {
 }
 const _: () = assert!(size_of::<IdtDescriptor>() == 16);
 impl IdtDescriptor {
-    fn new(
-        segment_selector: u16,
-        ist_index: u8,
-        attr: IdtAttr,
-        f: unsafe extern "sysv64" fn(),
-    ) -> Self {
-        let handler_addr = f as *const unsafe extern "sysv64" fn() as usize;
+    fn new(segment_selector: u16, ist_index: u8, attr: IdtAttr, handler_addr: u64) -> Self {
         Self {
             offset_low: handler_addr as u16,
             offset_mid: (handler_addr >> 16) as u16,
@@ -619,55 +1132,51 @@ struct IdtrParameters {
 const _: () = assert!(size_of::<IdtrParameters>() == 10);
 const _: () = assert!(offset_of!(IdtrParameters, base) == 2);
 
+// Indexes of the interrupt stacks in the TSS. An interrupt that arrives on the
+// IST stack in use restarts from its top and overwrites the frames of the
+// running handler, so kinds of interrupts that can nest use different stacks.
+const IST_EXCEPTIONS: u8 = 1;
+const IST_DOUBLE_FAULT: u8 = 2;
+const IST_DEBUG: u8 = 3;
+const IST_IRQ: u8 = 4;
+const IST_BREAKPOINT: u8 = 5;
+const IST_NMI: u8 = 6;
+const IST_MACHINE_CHECK: u8 = 7;
+
+/// Returns the IST index and the attribute of the IDT entry for the vector.
+fn gate_for_vector(vector: usize) -> (u8, IdtAttr) {
+    match vector {
+        // #DB can be raised in other handlers (e.g. by a watchpoint)
+        1 => (IST_DEBUG, IdtAttr::IntGateDPL0),
+        // NMI and #MC are not blocked by IF, so they can arrive in any handler
+        2 => (IST_NMI, IdtAttr::IntGateDPL0),
+        // Set DPL=3 to allow user land to make this interrupt (e.g. via int3 op).
+        // #DB can be raised while the GDB stub is handling it (e.g. by single-stepping).
+        3 => (IST_BREAKPOINT, IdtAttr::IntGateDPL3),
+        8 => (IST_DOUBLE_FAULT, IdtAttr::IntGateDPL0),
+        18 => (IST_MACHINE_CHECK, IdtAttr::IntGateDPL0),
+        // Use the current stack so that a stack overflow results in #DF
+        14 => (0, IdtAttr::IntGateDPL0),
+        v if v < IRQ_VECTOR_BASE as usize => (IST_EXCEPTIONS, IdtAttr::IntGateDPL0),
+        _ => (IST_IRQ, IdtAttr::IntGateDPL0),
+    }
+}
+
 pub struct Idt {
     #[allow(dead_code)]
     entries: Pin<Box<[IdtDescriptor; 0x100]>>,
 }
 impl Idt {
     pub fn new(segment_selector: u16) -> Self {
-        let mut entries = [IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            int_handler_unimplemented,
-        ); 0x100];
-        entries[3] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            // Set DPL=3 to allow user land to make this interrupt (e.g. via int3 op)
-            IdtAttr::IntGateDPL3,
-            interrupt_entrypoint3,
-        );
-        entries[6] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint6,
-        );
-        entries[8] = IdtDescriptor::new(
-            segment_selector,
-            2,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint8,
-        );
-        entries[13] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint13,
-        );
-        entries[14] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint14,
-        );
-        entries[32] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint32,
-        );
+        let entries: [IdtDescriptor; 0x100] = core::array::from_fn(|vector| {
+            let (ist_index, attr) = gate_for_vector(vector);
+            IdtDescriptor::new(
+                segment_selector,
+                ist_index,
+                attr,
+                interrupt_entrypoint(vector),
+            )
+        });
         let limit = size_of_val(&entries) as u16;
         let entries = Box::pin(entries);
         let params = IdtrParameters {
@@ -700,19 +1209,18 @@ impl TaskStateSegment64 {
     pub fn phys_addr(&self) -> u64 {
         self.inner.as_ref().get_ref() as *const TaskStateSegment64Inner as u64
     }
-    unsafe fn alloc_interrupt_stack() -> u64 {
+    /// The stack has a guard page only if init_paging() is done before this
+    /// (as efi_main() does). The unit tests use the stacks without them.
+    fn alloc_interrupt_stack(name: &'static str) -> u64 {
         const HANDLER_STACK_SIZE: usize = 64 * 1024;
-        let stack = Box::new([0u8; HANDLER_STACK_SIZE]);
-        let rsp = unsafe { stack.as_ptr().add(HANDLER_STACK_SIZE) as u64 };
-        core::mem::forget(stack);
-        // now, no one except us own the region since it is forgotten by the allocator ;)
-        rsp
+        alloc_kernel_stack(HANDLER_STACK_SIZE, name).expect("Failed to allocate an interrupt stack")
     }
     pub fn new() -> Self {
-        let rsp0 = unsafe { Self::alloc_interrupt_stack() };
+        const IST_NAMES: [&str; 8] = ["", "IST1", "IST2", "IST3", "IST4", "IST5", "IST6", "IST7"];
+        let rsp0 = Self::alloc_interrupt_stack("RSP0");
         let mut ist = [0u64; 8];
-        for ist in ist[1..=7].iter_mut() {
-            *ist = unsafe { Self::alloc_interrupt_stack() };
+        for (ist, name) in ist.iter_mut().zip(IST_NAMES).skip(1) {
+            *ist = Self::alloc_interrupt_stack(name);
         }
         let tss64 = TaskStateSegment64Inner {
             _reserved0: 0,
@@ -728,6 +1236,11 @@ impl TaskStateSegment64 {
         this
     }
 }
+impl Default for TaskStateSegment64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Drop for TaskStateSegment64 {
     fn drop(&mut self) {
         panic!("TSS64 being dropped!");
@@ -879,8 +1392,131 @@ pub unsafe fn write_cr3(table: *const PML4) {
     asm!("mov cr3, rax", in("rax") table)
 }
 
+/// Invalidates the TLB entries for the page that contains virt.
+/// This is harmless even if virt is not mapped by the current page table.
+pub fn invlpg(virt: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) virt) }
+}
+
 pub fn flush_tlb() {
     unsafe {
+        let cr4 = read_cr4();
+        if cr4 & CR4_PGE != 0 {
+            // Toggling CR4.PGE flushes the global pages as well.
+            write_cr4(cr4 & !CR4_PGE);
+            write_cr4(cr4);
+        }
         write_cr3(read_cr3());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    fn leaf_entry_value(table: &PML4, virt: u64) -> u64 {
+        let pdpt = table.entry[table.calc_index(virt)].table().unwrap();
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.is_huge_page() {
+            return pdpte.read_value();
+        }
+        let pd = pdpte.table().unwrap();
+        let pde = &pd.entry[pd.calc_index(virt)];
+        if pde.is_huge_page() {
+            return pde.read_value();
+        }
+        let pt = pde.table().unwrap();
+        pt.entry[pt.calc_index(virt)].read_value()
+    }
+
+    #[test_case]
+    fn page_attr_bits_are_kept_on_split() {
+        let mut table = PML4::new();
+        let virt = 0x4000_0000;
+        table
+            .create_mapping(
+                virt,
+                virt + 0x20_0000,
+                0x20_0000,
+                PageAttr::WriteCombiningKernel,
+            )
+            .unwrap();
+        let huge = leaf_entry_value(&table, virt);
+        assert!(huge & ATTR_PAGE_SIZE != 0);
+        assert!(huge & ATTR_PAT_HUGE != 0);
+        assert!(huge & ATTR_NO_EXECUTE != 0);
+        // Split the 2MiB page by remapping its last 4KiB page
+        table
+            .create_mapping(
+                virt + 0x1F_F000,
+                virt + 0x20_0000,
+                0,
+                PageAttr::ReadOnlyKernel,
+            )
+            .unwrap();
+        let small = leaf_entry_value(&table, virt);
+        assert_eq!(
+            small,
+            0x20_0000 | ATTR_PRESENT | ATTR_WRITABLE | ATTR_GLOBAL | ATTR_NO_EXECUTE | ATTR_PAT
+        );
+        assert_eq!(
+            leaf_entry_value(&table, virt + 0x1F_F000),
+            PageAttr::ReadOnlyKernel as u64
+        );
+        table.unmap(virt, virt + 0x20_0000).unwrap();
+    }
+
+    #[test_case]
+    fn user_pages_are_accessible_at_all_levels() {
+        let mut table = PML4::new();
+        let virt = 0x1_0000_0000;
+        table
+            .create_mapping(virt, virt + 0x1000, 0x3000, PageAttr::ReadExecuteUser)
+            .unwrap();
+        let pml4e = &table.entry[table.calc_index(virt)];
+        assert!(pml4e.is_user());
+        let pdpt = pml4e.table().unwrap();
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        assert!(pdpte.is_user());
+        let pd = pdpte.table().unwrap();
+        let pde = &pd.entry[pd.calc_index(virt)];
+        assert!(pde.is_user());
+        let pt = pde.table().unwrap();
+        let pte = &pt.entry[pt.calc_index(virt)];
+        assert!(pte.is_user() && !pte.is_writable());
+        let s = format!("{pte:?}");
+        assert!(s.contains("PRUX"), "{s}");
+        table.unmap(virt, virt + 0x1000).unwrap();
+    }
+
+    #[test_case]
+    fn nesting_interrupts_use_separate_stacks() {
+        let ist = |vector| gate_for_vector(vector).0;
+        assert_eq!(ist(14), 0);
+        assert_eq!(ist(13), IST_EXCEPTIONS);
+        assert_eq!(ist(0xFF), IST_IRQ);
+        // Each of them has its own stack
+        let vectors = [13, 1, 2, 3, 8, 18, IRQ_VECTOR_BASE as usize];
+        for (i, a) in vectors.iter().enumerate() {
+            assert!((1..=7).contains(&ist(*a)));
+            for b in &vectors[i + 1..] {
+                assert_ne!(ist(*a), ist(*b), "vector {a} and {b}");
+            }
+        }
+    }
+
+    #[test_case]
+    fn entry_format_shows_all_bits() {
+        let mut table = PML4::new();
+        let virt = 0x4000_0000;
+        table
+            .create_mapping(virt, virt + 0x20_0000, 0, PageAttr::WriteCombiningKernel)
+            .unwrap();
+        let pdpt = table.entry[table.calc_index(virt)].table().unwrap();
+        let pd = pdpt.entry[pdpt.calc_index(virt)].table().unwrap();
+        let s = format!("{:?}", pd.entry[pd.calc_index(virt)]);
+        assert!(s.contains("PWS- PS G PAT }"), "{s}");
+        table.unmap(virt, virt + 0x20_0000).unwrap();
+    }
+}